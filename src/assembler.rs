@@ -0,0 +1,502 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::merkle::{leaf_hash, root_of, MerkleTree, BLOCK_SIZE};
+use crate::retransmit::ReceivedBitmap;
+
+#[derive(Debug)]
+pub enum AssembleError {
+	OutOfRange { index: u32 },
+	WrongLength { index: u32, expected: u64, received: u64 },
+	// The payloads of the block have to be fetched again
+	CorruptBlock { block: u32, payloads: Range<u32> },
+	HashMismatch,
+	Io(io::Error),
+}
+
+impl fmt::Display for AssembleError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AssembleError::OutOfRange { index } => write!(f, "payload {} is past the end of the file", index),
+			AssembleError::WrongLength {
+				index,
+				expected,
+				received,
+			} => write!(f, "payload {} has {} bytes instead of {}", index, received, expected),
+			AssembleError::CorruptBlock { block, .. } => write!(f, "block {} does not match its hash", block),
+			AssembleError::HashMismatch => write!(f, "the downloaded file does not match the announced hash"),
+			AssembleError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+// What a download fetches, identified by its content rather than the hosts it comes
+// from or the size of their payloads. A partial download is only resumed if all of it matches.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DownloadIdentity {
+	pub merkle_root: [u8; 32],
+	pub file_size: u64,
+}
+
+// Progress of a partial download, stored next to its `.part` file. It records the blocks
+// that arrived in full, so a download resumes whatever payload size the next session uses.
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+	identity: DownloadIdentity,
+	blocks: ReceivedBitmap,
+}
+
+// The progress is saved at least this often while payloads arrive
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Puts a downloaded file together from payloads arriving in any order. Every payload
+// is written at its own offset, so duplicates and reordering are harmless. The file
+// is assembled as `<name>.part` and only renamed to its name once it is complete.
+pub struct FileAssembler {
+	file: File,
+	path: PathBuf,
+	part_path: PathBuf,
+	sidecar_path: PathBuf,
+	identity: DownloadIdentity,
+	payload_size: u32,
+	received: ReceivedBitmap,
+	// Hashes of the blocks of the file, once they were checked against the root. A block is
+	// checked as soon as all of its payloads arrived.
+	block_hashes: Option<Vec<[u8; 32]>>,
+	last_saved: Instant,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut path = path.as_os_str().to_owned();
+	path.push(suffix);
+	PathBuf::from(path)
+}
+
+// Blocks a file is hashed in, an empty file has an empty one
+fn block_count(file_size: u64) -> u32 {
+	file_size.div_ceil(BLOCK_SIZE.into()).max(1) as u32
+}
+
+// Blocks that hold part of the bytes from `start` to `end`
+fn blocks_of(start: u64, end: u64) -> Range<u32> {
+	if start >= end {
+		return 0..0;
+	}
+	(start / u64::from(BLOCK_SIZE)) as u32..end.div_ceil(u64::from(BLOCK_SIZE)) as u32
+}
+
+impl FileAssembler {
+	// Resume the partial download of `path` if it fetched the same content, or start over
+	pub fn open(path: &Path, identity: DownloadIdentity, payload_size: u32) -> io::Result<Self> {
+		if payload_size == 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "payloads must not be empty"));
+		}
+		let payload_count = identity.file_size.div_ceil(payload_size.into());
+		let payload_count = u32::try_from(payload_count)
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the file has too many payloads"))?;
+		let block_count = block_count(identity.file_size);
+
+		let part_path = with_suffix(path, ".part");
+		let sidecar_path = with_suffix(path, ".part.progress");
+
+		let sidecar = fs::read(&sidecar_path)
+			.ok()
+			.and_then(|contents| bincode::deserialize::<Sidecar>(&contents).ok())
+			.filter(|sidecar| sidecar.identity == identity && sidecar.blocks.len() == block_count);
+		let part_size = fs::metadata(&part_path).map(|metadata| metadata.len()).ok();
+
+		let (file, received) = match sidecar {
+			Some(sidecar) if part_size == Some(identity.file_size) => {
+				// Payloads of this session that lie in blocks which arrived in full
+				let mut received = ReceivedBitmap::new(payload_count);
+				for index in 0..payload_count {
+					let start = u64::from(index) * u64::from(payload_size);
+					let end = (start + u64::from(payload_size)).min(identity.file_size);
+					if blocks_of(start, end).all(|block| sidecar.blocks.contains(block)) {
+						received.insert(index);
+					}
+				}
+				(OpenOptions::new().write(true).read(true).open(&part_path)?, received)
+			}
+			_ => {
+				let file = OpenOptions::new()
+					.write(true)
+					.read(true)
+					.create(true)
+					.truncate(true)
+					.open(&part_path)?;
+				file.set_len(identity.file_size)?;
+				(file, ReceivedBitmap::new(payload_count))
+			}
+		};
+
+		let mut assembler = FileAssembler {
+			file,
+			path: path.to_path_buf(),
+			part_path,
+			sidecar_path,
+			identity,
+			payload_size,
+			received,
+			block_hashes: None,
+			last_saved: Instant::now(),
+		};
+		assembler.save_progress()?;
+		Ok(assembler)
+	}
+
+	pub fn payload_count(&self) -> u32 {
+		self.received.len()
+	}
+
+	pub fn file_size(&self) -> u64 {
+		self.identity.file_size
+	}
+
+	pub fn received(&self) -> &ReceivedBitmap {
+		&self.received
+	}
+
+	pub fn is_complete(&self) -> bool {
+		self.received.is_complete()
+	}
+
+	pub fn block_count(&self) -> u32 {
+		block_count(self.identity.file_size)
+	}
+
+	// Check every block against its hash from now on. The hashes must add up to the root.
+	pub fn verify_blocks(&mut self, hashes: Vec<[u8; 32]>) -> Result<(), AssembleError> {
+		if hashes.len() != self.block_count() as usize || root_of(&hashes) != self.identity.merkle_root {
+			return Err(AssembleError::HashMismatch);
+		}
+		self.block_hashes = Some(hashes);
+		Ok(())
+	}
+
+	// Payloads that hold part of a block
+	fn payloads_of(&self, block: u32) -> Range<u32> {
+		let payload_size = u64::from(self.payload_size);
+		let start = u64::from(block) * u64::from(BLOCK_SIZE);
+		let end = (start + u64::from(BLOCK_SIZE)).min(self.identity.file_size);
+		(start / payload_size) as u32..end.div_ceil(payload_size) as u32
+	}
+
+	// Check the blocks a payload completed against their hashes. The payloads of a block that
+	// does not match are no longer received, so they are fetched again.
+	fn check_blocks(&mut self, offset: u64, length: u64) -> Result<(), AssembleError> {
+		let Some(hashes) = &self.block_hashes else {
+			return Ok(());
+		};
+		if length == 0 {
+			return Ok(());
+		}
+
+		for block in blocks_of(offset, offset + length) {
+			let payloads = self.payloads_of(block);
+			if !payloads.clone().all(|index| self.received.contains(index)) {
+				continue;
+			}
+
+			let start = u64::from(block) * u64::from(BLOCK_SIZE);
+			let mut data = vec![0; ((start + u64::from(BLOCK_SIZE)).min(self.identity.file_size) - start) as usize];
+			self.file.seek(SeekFrom::Start(start)).map_err(AssembleError::Io)?;
+			self.file.read_exact(&mut data).map_err(AssembleError::Io)?;
+			if leaf_hash(&data) != hashes[block as usize] {
+				for index in payloads.clone() {
+					self.received.remove(index);
+				}
+				return Err(AssembleError::CorruptBlock { block, payloads });
+			}
+		}
+		Ok(())
+	}
+
+	// Bytes the payload at `index` must have, only the last one may be short
+	fn expected_length(&self, index: u32) -> u64 {
+		let payload_size = u64::from(self.payload_size);
+		(self.identity.file_size - u64::from(index) * payload_size).min(payload_size)
+	}
+
+	// Write a decrypted payload at its offset, returns false if it was already written
+	pub fn write_payload(&mut self, index: u32, data: &[u8]) -> Result<bool, AssembleError> {
+		if index >= self.payload_count() {
+			return Err(AssembleError::OutOfRange { index });
+		}
+
+		let expected = self.expected_length(index);
+		if data.len() as u64 != expected {
+			return Err(AssembleError::WrongLength {
+				index,
+				expected,
+				received: data.len() as u64,
+			});
+		}
+
+		if self.received.contains(index) {
+			return Ok(false);
+		}
+
+		let offset = u64::from(index) * u64::from(self.payload_size);
+		self.file.seek(SeekFrom::Start(offset)).map_err(AssembleError::Io)?;
+		self.file.write_all(data).map_err(AssembleError::Io)?;
+		self.received.insert(index);
+		self.check_blocks(offset, expected)?;
+
+		if self.last_saved.elapsed() >= SAVE_INTERVAL {
+			self.save_progress().map_err(AssembleError::Io)?;
+		}
+		Ok(true)
+	}
+
+	// Record which blocks are on disk, the data is synced first so the sidecar never claims more
+	pub fn save_progress(&mut self) -> io::Result<()> {
+		self.file.sync_data()?;
+
+		let mut blocks = ReceivedBitmap::new(self.block_count());
+		for block in 0..self.block_count() {
+			if self.payloads_of(block).all(|index| self.received.contains(index)) {
+				blocks.insert(block);
+			}
+		}
+		let sidecar = Sidecar {
+			identity: self.identity.clone(),
+			blocks,
+		};
+		let contents = bincode::serialize(&sidecar).map_err(io::Error::other)?;
+
+		// Replace the sidecar atomically, a crash must not leave a torn one behind
+		let staging_path = with_suffix(&self.sidecar_path, ".tmp");
+		fs::write(&staging_path, contents)?;
+		fs::rename(&staging_path, &self.sidecar_path)?;
+
+		self.last_saved = Instant::now();
+		Ok(())
+	}
+
+	// Check the complete file against the announced root and move it to its name
+	pub fn finish(self) -> Result<(), AssembleError> {
+		self.file.sync_all().map_err(AssembleError::Io)?;
+
+		let tree = MerkleTree::from_file(&self.part_path).map_err(AssembleError::Io)?;
+		if tree.root() != self.identity.merkle_root {
+			// Whatever went wrong, resuming would not fix it
+			let _ = fs::remove_file(&self.sidecar_path);
+			let _ = fs::remove_file(&self.part_path);
+			return Err(AssembleError::HashMismatch);
+		}
+
+		fs::rename(&self.part_path, &self.path).map_err(AssembleError::Io)?;
+		let _ = fs::remove_file(&self.sidecar_path);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+	use rand::seq::SliceRandom;
+
+	const PAYLOAD_SIZE: u32 = 16;
+
+	fn test_data() -> Vec<u8> {
+		(0..200u32).map(|i| (i * 7) as u8).collect()
+	}
+
+	fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+		data.chunks(BLOCK_SIZE as usize).map(leaf_hash).collect()
+	}
+
+	fn identity(data: &[u8]) -> DownloadIdentity {
+		DownloadIdentity {
+			merkle_root: root_of(&block_hashes(data)),
+			file_size: data.len() as u64,
+		}
+	}
+
+	fn payloads(data: &[u8]) -> Vec<(u32, &[u8])> {
+		data.chunks(PAYLOAD_SIZE as usize)
+			.enumerate()
+			.map(|(index, chunk)| (index as u32, chunk))
+			.collect()
+	}
+
+	#[test]
+	fn test_shuffled_delivery() {
+		let dir = TempDir::new("assembler");
+		let path = dir.join("download");
+		let data = test_data();
+
+		// Deliver every payload twice, in random order
+		let mut payloads = payloads(&data);
+		payloads.extend(payloads.clone());
+		payloads.shuffle(&mut rand::thread_rng());
+
+		let mut assembler = FileAssembler::open(&path, identity(&data), PAYLOAD_SIZE).unwrap();
+		let part_path = with_suffix(&path, ".part");
+		assert_eq!(fs::metadata(&part_path).unwrap().len(), 200, "The file must be preallocated");
+
+		let mut written = 0;
+		for (index, chunk) in payloads {
+			if assembler.write_payload(index, chunk).unwrap() {
+				written += 1;
+			}
+		}
+		assert_eq!(written, 13);
+		assert!(assembler.is_complete());
+		assembler.finish().unwrap();
+
+		let assembled = fs::read(&path).unwrap();
+		assert_eq!(assembled, data);
+		assert!(!part_path.exists());
+		assert!(!with_suffix(&path, ".part.progress").exists());
+	}
+
+	#[test]
+	fn test_resume() {
+		let dir = TempDir::new("assembler");
+		let path = dir.join("download");
+		let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| (i * 7) as u8).collect();
+
+		// The first download is interrupted with every block but the second complete
+		let mut assembler = FileAssembler::open(&path, identity(&data), 1024).unwrap();
+		for (index, chunk) in data.chunks(1024).enumerate() {
+			let block = index as u32 * 1024 / BLOCK_SIZE;
+			if block != 1 || index % 2 == 0 {
+				assembler.write_payload(index as u32, chunk).unwrap();
+			}
+		}
+		assembler.save_progress().unwrap();
+		drop(assembler);
+
+		// The next one uses other payloads, those in complete blocks are kept
+		let payload_size = 1429;
+		let mut assembler = FileAssembler::open(&path, identity(&data), payload_size).unwrap();
+		let payloads: Vec<&[u8]> = data.chunks(payload_size as usize).collect();
+		assert!(assembler.received().contains(0));
+		assert!(!assembler.received().contains(BLOCK_SIZE / payload_size), "It holds part of the second block");
+		assert!(assembler.received().contains(2 * BLOCK_SIZE / payload_size + 1));
+		for (index, payload) in payloads.iter().enumerate() {
+			let start = index as u64 * u64::from(payload_size);
+			let expected_new = blocks_of(start, start + payload.len() as u64).contains(&1);
+			assert_eq!(assembler.write_payload(index as u32, payload).unwrap(), expected_new);
+		}
+		assembler.finish().unwrap();
+
+		let assembled = fs::read(&path).unwrap();
+		assert_eq!(assembled, data);
+	}
+
+	#[test]
+	fn test_other_content_starts_over() {
+		let dir = TempDir::new("assembler");
+		let path = dir.join("download");
+		let data = test_data();
+
+		let mut assembler = FileAssembler::open(&path, identity(&data), PAYLOAD_SIZE).unwrap();
+		assembler.write_payload(0, &data[..16]).unwrap();
+		assembler.save_progress().unwrap();
+		drop(assembler);
+
+		// The host shares another version of the file now
+		let mut other = identity(&data);
+		other.merkle_root = [0u8; 32];
+		let assembler = FileAssembler::open(&path, other, PAYLOAD_SIZE).unwrap();
+		assert!(!assembler.received().contains(0));
+	}
+
+	#[test]
+	fn test_corrupt_download() {
+		let dir = TempDir::new("assembler");
+		let path = dir.join("download");
+		let data = test_data();
+
+		let mut assembler = FileAssembler::open(&path, identity(&data), PAYLOAD_SIZE).unwrap();
+		for (index, chunk) in payloads(&data) {
+			let chunk = if index == 3 { &[0u8; 16][..] } else { chunk };
+			assembler.write_payload(index, chunk).unwrap();
+		}
+
+		assert!(matches!(assembler.finish(), Err(AssembleError::HashMismatch)));
+		assert!(!path.exists());
+		assert!(!with_suffix(&path, ".part").exists());
+	}
+
+	#[test]
+	fn test_blocks_are_verified() {
+		let dir = TempDir::new("assembler");
+		let path = dir.join("download");
+		let data: Vec<u8> = (0..2 * BLOCK_SIZE + 100).map(|i| (i * 11) as u8).collect();
+		// Payloads of any size, some of them hold the end of one block and the start of the next
+		let payload_size = 1429;
+		let hashes = block_hashes(&data);
+
+		let mut assembler = FileAssembler::open(&path, identity(&data), payload_size).unwrap();
+		let mut forged = hashes.clone();
+		forged[1] = [0u8; 32];
+		assert!(matches!(assembler.verify_blocks(forged), Err(AssembleError::HashMismatch)));
+		assert!(matches!(
+			assembler.verify_blocks(hashes[..2].to_vec()),
+			Err(AssembleError::HashMismatch)
+		));
+		assembler.verify_blocks(hashes).unwrap();
+
+		// A tampered payload is caught once its block is complete, and the block is fetched again
+		let payloads: Vec<&[u8]> = data.chunks(payload_size as usize).collect();
+		let tampered_index = BLOCK_SIZE / payload_size + 3;
+		let mut tampered = payloads[tampered_index as usize].to_vec();
+		tampered[5] ^= 1;
+		assert!(assembler.write_payload(tampered_index, &tampered).unwrap());
+
+		let mut corrupt = None;
+		for (index, payload) in payloads.iter().enumerate() {
+			match assembler.write_payload(index as u32, payload) {
+				Ok(_) => {}
+				Err(AssembleError::CorruptBlock { block, payloads }) => corrupt = Some((block, payloads)),
+				Err(e) => panic!("{}", e),
+			}
+		}
+		let (block, refetch) = corrupt.unwrap();
+		assert_eq!(block, 1);
+		assert!(refetch.contains(&tampered_index));
+		assert!(refetch.clone().all(|index| !assembler.received().contains(index)));
+		assert!(assembler.received().contains(0), "The other blocks are kept");
+
+		for index in refetch {
+			assert!(assembler.write_payload(index, payloads[index as usize]).unwrap());
+		}
+		assembler.finish().unwrap();
+
+		let assembled = fs::read(&path).unwrap();
+		assert_eq!(assembled, data);
+	}
+
+	#[test]
+	fn test_malformed_payloads() {
+		let dir = TempDir::new("assembler");
+		let path = dir.join("download");
+		let mut assembler = FileAssembler::open(&path, identity(&[0u8; 40]), PAYLOAD_SIZE).unwrap();
+
+		assert!(matches!(
+			assembler.write_payload(3, &[0u8; 16]),
+			Err(AssembleError::OutOfRange { index: 3 })
+		));
+		assert!(matches!(
+			assembler.write_payload(0, &[0u8; 8]),
+			Err(AssembleError::WrongLength { expected: 16, .. })
+		));
+		// Only the last payload is short
+		assert!(matches!(
+			assembler.write_payload(2, &[0u8; 16]),
+			Err(AssembleError::WrongLength { expected: 8, .. })
+		));
+		assert!(assembler.write_payload(2, &[0u8; 8]).unwrap());
+		assert!(!assembler.is_complete());
+	}
+}
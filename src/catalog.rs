@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rsa::RsaPrivateKey;
+use tar::Builder;
+
+use crate::content::ContentReader;
+use crate::encryption::{
+	derive_key, generate_nonce_seed, generate_public_key, generate_salt, identity_fingerprint,
+	load_or_generate_identity, public_key_to_string, sign, PUBLIC_SHARE_KEY,
+};
+use crate::logger::{log_error, log_info};
+use crate::merkle::MerkleTree;
+use crate::policy::Availability;
+use crate::types::{Catalog, CatalogEntry, KdfParams, PackagingType, UploaderInfo};
+use crate::utils::redit_dir;
+
+// A file the host serves with its signed announcement, open for as long as it is shared
+#[derive(Clone)]
+pub struct HostedShare {
+	pub info: UploaderInfo,
+	pub content: Arc<ContentReader>,
+	// Clients check every payload and the whole download against the Merkle tree of the content
+	pub tree: Arc<MerkleTree>,
+	// The tarball a shared directory was packed into, it goes when the share does
+	pub tarball: Option<PathBuf>,
+}
+
+impl HostedShare {
+	pub fn entry(&self) -> CatalogEntry {
+		CatalogEntry {
+			share_id: self.info.share_id,
+			name: self.info.file_name.clone(),
+			files_size: self.info.files_size,
+			packaging: self.info.packaging.clone(),
+			availability: self.info.availability,
+		}
+	}
+
+	// Remove what was written for the share, once it is no longer shared
+	pub fn discard(&self) -> io::Result<()> {
+		match &self.tarball {
+			Some(tarball) => fs::remove_file(tarball),
+			None => Ok(()),
+		}
+	}
+}
+
+// What every share of a host is announced and logged in to with
+#[derive(Clone)]
+pub struct HostProfile {
+	pub public: bool,
+	pub name: String,
+	pub public_key: String,
+	pub identity: RsaPrivateKey,
+	salt: Option<String>,
+	kdf_params: KdfParams,
+	// The share key only depends on the passphrase and the salt, so it is derived once
+	pub share_key: [u8; 32],
+}
+
+impl HostProfile {
+	// Load the host's persistent identity and derive the key its shares are logged in to with
+	pub fn new(public: bool, name: String, password: &str, kdf_params: KdfParams) -> Option<Self> {
+		let identity_path = redit_dir().join("identity.pem");
+		let identity = match load_or_generate_identity(&identity_path) {
+			Ok(key) => key,
+			Err(e) => {
+				log_error(&format!("Failed to load the identity key {}: {}", identity_path.display(), e));
+				return None;
+			}
+		};
+		let public_key = public_key_to_string(&generate_public_key(identity.clone()));
+		log_info(&format!("Host identity: {}", identity_fingerprint(&public_key)));
+
+		let (salt, share_key) = if public {
+			(None, PUBLIC_SHARE_KEY)
+		} else {
+			let (salt, share_key) = derive_share_key(password, &kdf_params)?;
+			(Some(salt), share_key)
+		};
+
+		Some(HostProfile {
+			public,
+			name,
+			public_key,
+			identity,
+			salt,
+			kdf_params,
+			share_key,
+		})
+	}
+
+	// The profile with the share key of another passphrase, under a new salt. Sessions that
+	// already logged in keep their keys.
+	pub fn with_passphrase(&self, password: &str) -> Option<HostProfile> {
+		let (salt, share_key) = derive_share_key(password, &self.kdf_params)?;
+		Some(HostProfile {
+			salt: Some(salt),
+			share_key,
+			..self.clone()
+		})
+	}
+
+	// The share announced with the salt of this profile, and the availability it has left
+	pub fn reannounce(&self, share: &HostedShare, availability: Availability) -> HostedShare {
+		let mut share = share.clone();
+		share.info.hashed_connection_salt = self.salt.clone();
+		share.info.availability = availability;
+		share.info.signature = sign(&self.identity, &share.info.signed_bytes());
+		share
+	}
+
+	// Open a file for sharing as `file_name` and sign its announcement
+	pub fn open_share(
+		&self,
+		share_id: u32,
+		file_path: &Path,
+		file_name: String,
+		packaging: PackagingType,
+		availability: Availability,
+	) -> io::Result<HostedShare> {
+		let content = ContentReader::open(file_path)?;
+		let tree = MerkleTree::from_file(file_path)?;
+
+		let mut info = UploaderInfo {
+			public: self.public,
+			name: self.name.clone(),
+			share_id,
+			files_size: content.size(),
+			file_name,
+			packaging,
+			public_key: Some(self.public_key.clone()),
+			hashed_connection_salt: self.salt.clone(),
+			kdf_params: self.kdf_params,
+			// Every share has its own nonces, even under the same session key
+			nonce_seed: generate_nonce_seed(),
+			merkle_root: tree.root(),
+			availability,
+			signature: Vec::new(),
+		};
+		// Signed again by `reannounce` when the passphrase or the downloads left change
+		info.signature = sign(&self.identity, &info.signed_bytes());
+
+		Ok(HostedShare {
+			info,
+			content: Arc::new(content),
+			tree: Arc::new(tree),
+			tarball: None,
+		})
+	}
+
+	// Share a file, or a directory as a tarball of it
+	pub fn share_path(&self, share_id: u32, path: &Path, availability: Availability) -> io::Result<HostedShare> {
+		let file_name = path
+			.file_name()
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path has no file name"))?
+			.to_string_lossy()
+			.to_string();
+
+		if !path.is_dir() {
+			return self.open_share(share_id, path, file_name, PackagingType::None, availability);
+		}
+
+		// Every share has its own tarball, rewriting one that is shared would change its content
+		let tar_path = redit_dir()
+			.join("tars")
+			.join(format!("{}-{}-{}.tar.gz", std::process::id(), share_id, file_name));
+		let share = tar_dir(path, &tar_path)
+			.and_then(|_| self.open_share(share_id, &tar_path, file_name, PackagingType::Tarred, availability));
+		match share {
+			Ok(share) => Ok(HostedShare {
+				tarball: Some(tar_path),
+				..share
+			}),
+			Err(e) => {
+				let _ = fs::remove_file(&tar_path);
+				Err(e)
+			}
+		}
+	}
+}
+
+// A fresh salt and the share key it derives from the passphrase
+fn derive_share_key(password: &str, kdf_params: &KdfParams) -> Option<(String, [u8; 32])> {
+	let salt = generate_salt();
+	match derive_key(password, &salt, kdf_params) {
+		Ok(share_key) => Some((salt, share_key)),
+		Err(e) => {
+			log_error(&format!("Failed to derive the share key: {}", e));
+			None
+		}
+	}
+}
+
+// Make a tar of the directory
+fn tar_dir(dir_path: &Path, tar_path: &Path) -> io::Result<()> {
+	if let Some(parent) = tar_path.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	let tar_gz = File::create(tar_path)?;
+	let enc = GzEncoder::new(tar_gz, Compression::default());
+	let mut tar = Builder::new(enc);
+
+	tar.append_dir_all(".", dir_path)?;
+	tar.into_inner()?.finish()?;
+	Ok(())
+}
+
+// The shares of a host by their id. Ids are never reused, so a session for a share that
+// was removed is never served another one.
+#[derive(Default)]
+pub struct Shares {
+	shares: BTreeMap<u32, Arc<HostedShare>>,
+	next_id: u32,
+}
+
+impl Shares {
+	// Take the id of the next share. Shares are opened with their id but without holding
+	// the catalog, hashing a large file must not hold up the clients.
+	pub fn next_id(&mut self) -> u32 {
+		let share_id = self.next_id;
+		self.next_id += 1;
+		share_id
+	}
+
+	pub fn insert(&mut self, share: HostedShare) {
+		self.shares.insert(share.info.share_id, Arc::new(share));
+	}
+
+	pub fn remove(&mut self, share_id: u32) -> Option<Arc<HostedShare>> {
+		self.shares.remove(&share_id)
+	}
+
+	pub fn get(&self, share_id: u32) -> Option<Arc<HostedShare>> {
+		self.shares.get(&share_id).cloned()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Arc<HostedShare>> {
+		self.shares.values()
+	}
+
+	// Replace a share with a new announcement of it, unless it changed or was removed since
+	// `current` was taken from the catalog. False if it was not replaced.
+	pub fn replace(&mut self, current: &Arc<HostedShare>, share: HostedShare) -> bool {
+		match self.shares.get_mut(&current.info.share_id) {
+			Some(stored) if Arc::ptr_eq(stored, current) => {
+				*stored = Arc::new(share);
+				true
+			}
+			_ => false,
+		}
+	}
+
+	// The shares from `start` on that fit a packet of `max_size` bytes. Shares that will not
+	// be served again are left out.
+	pub fn page(&self, start: u32, max_size: usize, now: u64) -> Catalog {
+		let entries = self
+			.shares
+			.range(start..)
+			.filter(|(_, share)| !share.info.availability.is_over(now))
+			.map(|(_, share)| share.entry());
+		Catalog::page(entries, max_size)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{announcement, TempDir};
+
+	fn share(share_id: u32, path: &Path) -> io::Result<HostedShare> {
+		Ok(HostedShare {
+			info: UploaderInfo {
+				public: true,
+				share_id,
+				files_size: 3,
+				file_name: format!("share-{}", share_id),
+				..announcement()
+			},
+			content: Arc::new(ContentReader::open(path)?),
+			tree: Arc::new(MerkleTree::from_file(path)?),
+			tarball: None,
+		})
+	}
+
+	#[test]
+	fn test_share_ids_are_not_reused() {
+		let dir = TempDir::new("catalog");
+		let path = dir.file("shared", b"abc");
+
+		let mut shares = Shares::default();
+		for _ in 0..3 {
+			let share_id = shares.next_id();
+			shares.insert(share(share_id, &path).unwrap());
+		}
+		assert!(shares.remove(1).is_some());
+		let share_id = shares.next_id();
+		shares.insert(share(share_id, &path).unwrap());
+
+		let catalog = shares.page(0, 1200, 0);
+		let ids: Vec<u32> = catalog.shares.iter().map(|entry| entry.share_id).collect();
+		assert_eq!(ids, vec![0, 2, 3]);
+		assert_eq!(catalog.shares[2].name, "share-3");
+		assert_eq!(shares.page(1, 1200, 0).shares.len(), 2);
+		assert!(shares.get(1).is_none());
+	}
+
+	#[test]
+	fn test_catalog_leaves_out_shares_that_are_over() {
+		let dir = TempDir::new("catalog");
+		let path = dir.file("shared", b"abc");
+
+		let mut shares = Shares::default();
+		for availability in [
+			Availability::default(),
+			Availability {
+				expires_at: Some(100),
+				..Availability::default()
+			},
+			Availability {
+				downloads_left: Some(1),
+				..Availability::default()
+			},
+		] {
+			let mut share = share(shares.next_id(), &path).unwrap();
+			share.info.availability = availability;
+			shares.insert(share);
+		}
+		assert_eq!(shares.page(0, 1200, 50).shares.len(), 3);
+		assert_eq!(shares.page(0, 1200, 100).shares.len(), 2, "Expired shares are not listed");
+
+		let current = shares.get(2).unwrap();
+		let mut exhausted = (*current).clone();
+		exhausted.info.availability.downloads_left = Some(0);
+		assert!(shares.replace(&current, exhausted.clone()));
+		assert!(!shares.replace(&current, exhausted), "A share that changed meanwhile is not replaced");
+		let ids: Vec<u32> = shares.page(0, 1200, 100).shares.iter().map(|entry| entry.share_id).collect();
+		assert_eq!(ids, vec![0]);
+	}
+}
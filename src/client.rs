@@ -1,185 +1,566 @@
-use crate::encryption::{decrypt_with_passphrase, derive_key, public_key_from_string};
-use crate::logger::{log_error, log_info};
+use crate::encryption::{
+	client_confirmation, confirmation_matches, derive_key, derive_traffic_key, finish_login,
+	generate_ephemeral_key, identity_fingerprint, server_confirmation, start_client_login, verify_signature,
+	PayloadCipher, PUBLIC_SHARE_KEY,
+};
+use crate::assembler::{AssembleError, DownloadIdentity, FileAssembler};
+use crate::congestion::CongestionControl;
+use crate::fec::RepairDecoder;
+use crate::known_hosts::{is_valid_field, KnownHosts, Trust};
+use crate::logger::{log_error, log_info, log_success, log_warning};
+use crate::mtu::{discover_datagram_size, payload_size_for};
+use crate::policy::unix_now;
+use crate::retransmit::{RetriesExhausted, RetryPolicy};
 use crate::scan;
-use crate::types::{PackagingType, Payload, ReditPacket, RequestPayload, UploaderInfo, PAYLOAD_SIZE, PORT};
-use rand::rngs::OsRng;
-use rsa::pkcs1v15::Pkcs1v15Encrypt;
-use std::collections::HashSet;
-use std::fs;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
-use std::io::{SeekFrom, Write};
+use crate::server;
+use crate::swarm::Swarm;
+use crate::types::{
+	Capabilities, DownloadFinished, FecParams, Hello, LoginConfirm, LoginRequest, PackagingType, PacketError, Payload,
+	ReditPacket, RejectReason, RequestNodeHashes, RequestPayload, RequestPayloadRange, UploaderInfo,
+	AUTHENTICATION_TAG_SIZE, MAX_RANGE_LENGTH, PORT,
+};
+use crate::utils::redit_dir;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
 use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
-// Resolve a Payload from a ReditPacket.
-fn resolve_payload(packet: ReditPacket) -> Option<Payload> {
-	match packet {
-		ReditPacket::Payload(payload) => {
-			return Some(payload);
+const EXCHANGE_ATTEMPTS: u32 = 3;
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum ConnectError {
+	Rejected(RejectReason),
+	Incompatible(PacketError),
+	WrongPassword,
+	Impersonated,
+	Timeout,
+	SessionRejected,
+	PayloadSize(u32),
+	MissingHashes,
+	Io(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConnectError::Rejected(reason) => write!(f, "host rejected us: {}", reason),
+			ConnectError::Incompatible(e) => write!(f, "host is incompatible: {}", e),
+			ConnectError::WrongPassword => write!(f, "wrong password"),
+			ConnectError::Impersonated => write!(f, "host failed to prove its identity"),
+			ConnectError::Timeout => write!(f, "host did not answer"),
+			ConnectError::SessionRejected => write!(f, "host does not accept our session"),
+			ConnectError::PayloadSize(size) => write!(f, "host picked {} byte payloads, which our path cannot carry", size),
+			ConnectError::MissingHashes => write!(f, "host did not send the hashes of its blocks"),
+			ConnectError::Io(e) => write!(f, "{}", e),
 		}
-		_ => {
-			return None;
+	}
+}
+
+// Whether an announcement is signed by the identity it presents, and whether that is the
+// identity pinned for the host's name
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Verification {
+	Verified,
+	// Signed, but the name was never seen before so there is nothing to check the identity against
+	New,
+	// Signed by another identity than the one pinned for the name
+	Impostor,
+	Unverified,
+	Forged,
+}
+
+impl fmt::Display for Verification {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Verification::Verified => write!(f, "verified"),
+			Verification::New => write!(f, "new, signed"),
+			Verification::Impostor => write!(f, "IMPOSTOR"),
+			Verification::Unverified => write!(f, "UNVERIFIED"),
+			Verification::Forged => write!(f, "FORGED"),
 		}
 	}
 }
 
-fn pipeline_receive(
-	socket: UdpSocket,
-	tx: mpsc::Sender<Payload>,
-	start: u32,
-	end: u32,
-	start_byte: u64,
-	end_byte: u64,
-	payloads_in_transit: Arc<Mutex<HashSet<u32>>>,
-) {
-	let mut buf = [0; 524288];
-	let mut payloads_received: HashSet<u32> = Default::default();
-
-	let bar = indicatif::ProgressBar::new(end.into());
-	bar.set_style(
-		indicatif::ProgressStyle::default_bar()
-			.template("[{elapsed_precise}] {wide_bar} {binary_bytes}/{binary_total_bytes} {bytes_per_sec} [{eta}]")
-			.unwrap()
-			.progress_chars("#>-"),
-	);
-	bar.set_position(start_byte);
-	bar.set_length(end_byte);
-
-	// Recieve payloads
-	loop {
-		match socket.recv_from(&mut buf) {
-			Ok((_response_size, _respondee_address)) => {
-				match bincode::deserialize::<ReditPacket>(&buf) {
-					Ok(res) => {
-						let payload = match resolve_payload(res) {
-							Some(payload) => payload,
-							None => continue,
-						};
-						payloads_received.insert(payload.index);
-						payloads_in_transit.lock().unwrap().remove(&payload.index);
-						bar.inc(payload.data.len().try_into().unwrap());
-						match tx.send(payload) {
-							Ok(_) => {}
-							Err(_) => {}
-						};
-					}
-					Err(e) => {
-						log_error(&format!("Failed to deserialize packet: {}", e));
-					}
-				}
+pub fn verify_announcement(host_info: &UploaderInfo) -> Verification {
+	match host_info.public_key.as_deref() {
+		Some(public_key) if !host_info.signature.is_empty() => {
+			if verify_signature(public_key, &host_info.signed_bytes(), &host_info.signature) {
+				Verification::Verified
+			} else {
+				Verification::Forged
 			}
-			Err(ref e)
-			if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
-			{
-				break;
+		}
+		_ => Verification::Unverified,
+	}
+}
+
+// Check a signed announcement against the identity pinned for its name
+pub fn check_announcement(host_info: &UploaderInfo, known_hosts: &KnownHosts) -> Verification {
+	let verification = verify_announcement(host_info);
+	match host_info.public_key.as_deref() {
+		Some(public_key) if verification == Verification::Verified => match known_hosts.check(&host_info.name, public_key) {
+			Trust::Known => Verification::Verified,
+			Trust::New => Verification::New,
+			Trust::Changed { .. } => Verification::Impostor,
+		},
+		_ => verification,
+	}
+}
+
+fn known_hosts_path() -> PathBuf {
+	redit_dir().join("known_hosts")
+}
+
+// A session the host accepted our login for
+pub struct LoginSession {
+	pub id: u64,
+	pub key: [u8; 32],
+	pub payload_size: u32,
+	pub fec: Option<FecParams>,
+}
+
+// Check the host's identity against the one pinned for its name, pinning it on first use
+fn verify_host_identity(host_info: &UploaderInfo, host_ip: IpAddr) -> bool {
+	let public_key = match host_info.public_key.as_deref() {
+		Some(public_key) => public_key,
+		None => {
+			log_error("The host does not present an identity");
+			return false;
+		}
+	};
+
+	if !is_valid_field(&host_info.name) {
+		log_error("The host name contains control characters");
+		return false;
+	}
+
+	let known_hosts_path = known_hosts_path();
+	let mut known_hosts = match KnownHosts::load(&known_hosts_path) {
+		Ok(known_hosts) => known_hosts,
+		Err(e) => {
+			log_error(&format!("Failed to read {}: {}", known_hosts_path.display(), e));
+			return false;
+		}
+	};
+
+	let fingerprint = identity_fingerprint(public_key);
+	match known_hosts.check(&host_info.name, public_key) {
+		Trust::Known => {}
+		Trust::New => log_warning(&format!(
+			"First connection to {}, pinning its identity {}",
+			host_info.name, fingerprint
+		)),
+		Trust::Changed {
+			pinned_key,
+			pinned_address,
+		} => {
+			log_error("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+			log_error("@       WARNING: HOST IDENTIFICATION HAS CHANGED!         @");
+			log_error("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+			log_error(&format!(
+				"{} at {} presents identity {}, but {} was pinned when it was seen at {}.",
+				host_info.name,
+				host_ip,
+				fingerprint,
+				identity_fingerprint(&pinned_key),
+				pinned_address
+			));
+			log_error("Someone may be impersonating the host. If the host really changed its key,");
+			log_error(&format!("remove its line from {} to trust the new one.", known_hosts_path.display()));
+			return false;
+		}
+	}
+
+	// Remember the identity and the address it was last seen at
+	if let Err(e) = known_hosts.pin(&host_info.name, host_ip, public_key) {
+		log_error(&format!("Failed to write {}: {}", known_hosts_path.display(), e));
+	}
+	true
+}
+
+// Send a request to the host until it answers with a packet `accept` takes
+fn exchange<T>(
+	socket: &UdpSocket,
+	host_addr: SocketAddr,
+	request: &ReditPacket,
+	mut accept: impl FnMut(ReditPacket) -> Option<Result<T, ConnectError>>,
+) -> Result<T, ConnectError> {
+	let request = request.encode();
+	let mut buf = vec![0; 65536];
+
+	for _ in 0..EXCHANGE_ATTEMPTS {
+		socket.send_to(&request, host_addr).map_err(ConnectError::Io)?;
+
+		let deadline = Instant::now() + EXCHANGE_TIMEOUT;
+		while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+			socket
+				.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))
+				.map_err(ConnectError::Io)?;
+
+			let (amt, src) = match socket.recv_from(&mut buf) {
+				Ok(received) => received,
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+				Err(e) => return Err(ConnectError::Io(e)),
+			};
+			if src.ip() != host_addr.ip() {
+				continue;
 			}
-			Err(e) => {
-				log_error(&format!("Failed to receive packet: {}", e));
+
+			match ReditPacket::decode(&buf[..amt]) {
+				Ok(ReditPacket::Reject(reject)) => return Err(ConnectError::Rejected(reject.reason)),
+				Ok(packet) => {
+					if let Some(result) = accept(packet) {
+						return result;
+					}
+				}
+				Err(e @ PacketError::VersionMismatch { .. }) => return Err(ConnectError::Incompatible(e)),
+				Err(e) => log_error(&format!("Received a corrupt packet: {}", e)),
 			}
 		}
-		let payloads_remaining: usize = usize::try_from(end).unwrap()
-		- payloads_received.len()
-		- usize::try_from(start).unwrap();
-		if payloads_remaining == 0 {
-			break;
+	}
+
+	Err(ConnectError::Timeout)
+}
+
+// Exchange capabilities with a host before talking to it any further
+pub fn handshake(socket: &UdpSocket, host_addr: SocketAddr) -> Result<Capabilities, ConnectError> {
+	let local = Capabilities::local();
+	let hello = ReditPacket::Hello(Hello {
+		capabilities: local.clone(),
+	});
+
+	exchange(socket, host_addr, &hello, |packet| match packet {
+		// Double check that the host answered with something we can use
+		ReditPacket::Capabilities(remote) => Some(local.negotiate(&remote).map_err(ConnectError::Rejected)),
+		_ => None,
+	})
+}
+
+// Log in with SPAKE2, the host learns neither the passphrase nor the share key.
+// The session key additionally mixes in an ephemeral Diffie-Hellman secret, and
+// the host signs its half of the exchange with its identity key.
+pub fn login(
+	socket: &UdpSocket,
+	host_addr: SocketAddr,
+	share_id: u32,
+	share_key: &[u8; 32],
+	host_public_key: &str,
+	payload_size: u32,
+	fec: Option<FecParams>,
+) -> Result<LoginSession, ConnectError> {
+	let (state, spake_message) = start_client_login(share_key);
+	let (ephemeral_secret, ephemeral_public) = generate_ephemeral_key();
+	let request = LoginRequest {
+		spake_message,
+		ephemeral_public,
+		share_id,
+		payload_size,
+		fec,
+	};
+
+	let challenge = exchange(socket, host_addr, &ReditPacket::LoginRequest(request.clone()), |packet| {
+		match packet {
+			ReditPacket::LoginChallenge(challenge) => Some(Ok(challenge)),
+			_ => None,
 		}
+	})?;
+
+	// Make sure the pinned host, not someone in between, answered our login
+	if !verify_signature(host_public_key, &challenge.signed_bytes(&request), &challenge.signature) {
+		return Err(ConnectError::Impersonated);
+	}
+	if challenge.payload_size == 0 || challenge.payload_size > payload_size {
+		return Err(ConnectError::PayloadSize(challenge.payload_size));
+	}
+
+	// The host proves it knows the share key before we prove that we do
+	let session_key = finish_login(state, &challenge.spake_message)
+		.and_then(|login_key| {
+			derive_traffic_key(
+				&login_key,
+				ephemeral_secret,
+				&ephemeral_public,
+				&challenge.ephemeral_public,
+				&challenge.ephemeral_public,
+			)
+		})
+		.ok_or(ConnectError::WrongPassword)?;
+	if !confirmation_matches(server_confirmation(&session_key), challenge.confirmation) {
+		return Err(ConnectError::WrongPassword);
 	}
 
-	bar.finish();
+	let session_id = challenge.session_id;
+	let confirm = ReditPacket::LoginConfirm(LoginConfirm {
+		session_id,
+		confirmation: client_confirmation(&session_key),
+	});
+
+	let success = exchange(socket, host_addr, &confirm, |packet| match packet {
+		ReditPacket::LoginResult(result) if result.session_id == session_id => Some(Ok(result.success)),
+		_ => None,
+	})?;
+
+	if !success {
+		return Err(ConnectError::WrongPassword);
+	}
+
+	Ok(LoginSession {
+		id: session_id,
+		key: session_key,
+		payload_size: challenge.payload_size,
+		// Repair symbols we did not ask for are ignored
+		fec: challenge.fec.filter(|params| fec.is_some() && params.is_acceptable()),
+	})
+}
+
+// How often the transfer statistics under the progress bar are refreshed
+const STATS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub enum TransferError {
+	RetriesExhausted(RetriesExhausted),
+	SessionRejected,
+	Assemble(AssembleError),
+	Io(io::Error),
+}
+
+impl fmt::Display for TransferError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TransferError::RetriesExhausted(e) => write!(f, "gave up: {}", e),
+			TransferError::SessionRejected => write!(f, "host no longer accepts our session"),
+			TransferError::Assemble(e) => write!(f, "{}", e),
+			TransferError::Io(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+// Split payload indices into runs of consecutive ones no longer than a host serves, as start and length
+fn consecutive_runs(indices: &[u32]) -> Vec<(u32, u32)> {
+	let mut runs: Vec<(u32, u32)> = Vec::new();
+	for &index in indices {
+		match runs.last_mut() {
+			Some((start, count)) if *start + *count == index && *count < MAX_RANGE_LENGTH => *count += 1,
+			_ => runs.push((index, 1)),
+		}
+	}
+	runs
+}
+
+// Runs of payloads to request from a host now. Nothing is requested until a quarter of
+// the window is free, so every range request asks for many payloads rather than for the
+// one or two that just arrived.
+fn range_requests(
+	swarm: &mut Swarm,
+	lane: usize,
+	congestion: &CongestionControl,
+	now: Instant,
+) -> Result<Vec<(u32, u32)>, RetriesExhausted> {
+	let window = congestion.window();
+	let batch = (window / 4).clamp(1, MAX_RANGE_LENGTH as usize);
+	if window.saturating_sub(swarm.in_flight(lane)) < batch {
+		return Ok(Vec::new());
+	}
+	let requests = swarm.next_requests(lane, now, window, congestion.rto())?;
+	Ok(consecutive_runs(&requests))
+}
+
+// A host the download is fetched from
+pub struct Source {
+	pub name: String,
+	pub addr: SocketAddr,
+	pub session_id: u64,
+	pub share_id: u32,
+	pub cipher: PayloadCipher,
+	pub repair: Option<RepairDecoder>,
+}
+
+// Round trip time, window and loss of every source
+fn transfer_statistics(sources: &[Source], congestion: &[CongestionControl]) -> Vec<String> {
+	sources
+		.iter()
+		.zip(congestion)
+		.map(|(source, congestion)| format!("{}: {}", source.name, congestion.stats()))
+		.collect()
 }
 
-// Pipeline entrypoint
+fn log_transfer_statistics(sources: &[Source], congestion: &[CongestionControl]) {
+	for line in transfer_statistics(sources, congestion) {
+		log_info(&format!("Transfer statistics of {}", line));
+	}
+}
+
+// Fetch the payloads the assembler is missing from every source at once, requesting lost
+// payloads again until every one arrived. Sources that stop answering are dropped and
+// the others take over their payloads.
 pub fn get_payloads_via_pipeline(
-	server_addr: IpAddr,
-	hashed_password: Vec<u8>,
-	start: u32,
-	end: u32,
-	start_byte: u64,
-	end_byte: u64,
-	mut file: fs::File,
-	password: &str,
-) {
-	let payloads_in_transit: Arc<Mutex<HashSet<u32>>> = Default::default();
-	let payloads_in_transit_c = payloads_in_transit.clone();
-	let (tx, rx) = mpsc::channel::<Payload>();
-
-	log_info(&format!("Binding to address 0.0.0.0:{}", PORT));
-	let socket = UdpSocket::bind(format!("0.0.0.0:{}", PORT)).expect("Couldn't bind to address");
-	let listener_socket = socket.try_clone().unwrap();
-
-	let server_socket: SocketAddr = SocketAddr::new(server_addr, 6969);
-
-	let listener = thread::spawn(move || {
-		pipeline_receive(
-			listener_socket,
-			tx,
-			start,
-			end,
-			start_byte,
-			end_byte,
-			payloads_in_transit_c,
-		);
-	});
+	socket: &UdpSocket,
+	sources: &mut [Source],
+	assembler: &mut FileAssembler,
+	policy: RetryPolicy,
+) -> Result<(), TransferError> {
+	let end = assembler.payload_count();
+	let mut swarm = Swarm::new(end, sources.len(), policy, assembler.received());
+	let mut congestion: Vec<CongestionControl> = sources
+		.iter()
+		.map(|_| CongestionControl::new(policy.initial_timeout))
+		.collect();
 
-	let key = derive_key(password);
+	let bar = indicatif::ProgressBar::new(assembler.file_size());
+	bar.set_style(
+		indicatif::ProgressStyle::default_bar()
+			.template("[{elapsed_precise}] {wide_bar} {binary_bytes}/{binary_total_bytes} {bytes_per_sec} [{eta}]\n{msg}")
+			.unwrap()
+			.progress_chars("#>-"),
+	);
+	let mut stats_shown = Instant::now();
 
-	// Request payloads
-	for index in start..end {
-		loop {
-			let payloads_in_transit_count = payloads_in_transit.lock().unwrap().len();
-			if payloads_in_transit_count > 10 {
-				thread::sleep(Duration::from_millis(20))
-			} else {
-				break;
+	let mut buf = vec![0; 65536];
+	while !assembler.is_complete() {
+		// Every window shrinks when requests go unanswered and grows as payloads arrive
+		let now = Instant::now();
+		for (lane, source) in sources.iter().enumerate() {
+			if !swarm.is_active(lane) {
+				continue;
+			}
+
+			congestion[lane].on_lost(swarm.expire(lane, now), now);
+			let runs = match range_requests(&mut swarm, lane, &congestion[lane], now) {
+				Ok(runs) => runs,
+				Err(e) => {
+					log_warning(&format!("Dropping {} from the download: {}", source.name, e));
+					if swarm.drop_lane(lane) {
+						continue;
+					}
+					bar.abandon();
+					log_transfer_statistics(sources, &congestion);
+					return Err(TransferError::RetriesExhausted(e));
+				}
+			};
+
+			// Consecutive payloads are asked for in one request and streamed back at our pace
+			let interval_micros = u32::try_from(congestion[lane].pacing_interval().as_micros()).unwrap_or(u32::MAX);
+			for (start, count) in runs {
+				let request = ReditPacket::RequestPayloadRange(RequestPayloadRange {
+					session_id: source.session_id,
+					share_id: source.share_id,
+					start,
+					count,
+					interval_micros,
+				});
+				socket.send_to(&request.encode(), source.addr).map_err(TransferError::Io)?;
 			}
 		}
-		payloads_in_transit.lock().unwrap().insert(index);
 
-		let request_payload: RequestPayload = RequestPayload {
-			hashed_password: hashed_password.clone(),
-			payload_index: index,
+		// Wait for payloads until the next outstanding request is considered lost
+		let timeout = swarm
+			.next_deadline()
+			.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+			.unwrap_or_default()
+			.max(Duration::from_millis(1));
+		socket.set_read_timeout(Some(timeout)).map_err(TransferError::Io)?;
+
+		let (amt, src) = match socket.recv_from(&mut buf) {
+			Ok(received) => received,
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+			Err(e) => return Err(TransferError::Io(e)),
+		};
+		let Some(lane) = sources.iter().position(|source| source.addr == src) else {
+			continue;
 		};
-		let request_payload =
-		bincode::serialize(&ReditPacket::RequestPayload(request_payload)).unwrap();
-
-		socket
-			.send_to(&request_payload, server_socket)
-			.map_err(|e| e.to_string())
-			.unwrap();
-
-		match rx.try_recv() {
-			Ok(payload) => {
-				match file.seek(SeekFrom::Start(
-					u64::from(payload.index) * u64::from(PAYLOAD_SIZE),
-				)) {
-					Ok(_) => {}
-					Err(_) => file
-						.flush()
-						.expect("Unable to flush output file due to error"),
-				};
-				let data = decrypt_with_passphrase(&payload.data, &key);
-				file.write(&data).unwrap();
+		if !swarm.is_active(lane) {
+			continue;
+		}
+		let source = &mut sources[lane];
+
+		// Payloads that arrived, and the ones repair symbols rebuilt, marked as such
+		let mut arrived: VecDeque<(u32, Vec<u8>, bool)> = match ReditPacket::decode(&buf[..amt]) {
+			Ok(ReditPacket::Payload(payload)) => {
+				if !payload.success {
+					log_warning(&format!("Dropping {} from the download: {}", source.name, TransferError::SessionRejected));
+					if swarm.drop_lane(lane) {
+						continue;
+					}
+					bar.abandon();
+					return Err(TransferError::SessionRejected);
+				}
+				VecDeque::from([(payload.index, payload.data, false)])
+			}
+			Ok(ReditPacket::RepairSymbol(symbol)) => match source.repair.as_mut() {
+				Some(decoder) => decoder.on_repair(symbol).into_iter().map(|(index, data)| (index, data, true)).collect(),
+				None => continue,
+			},
+			Ok(_) => continue,
+			Err(e) => {
+				log_error(&format!("Received a corrupt packet: {}", e));
+				continue;
+			}
+		};
+
+		while let Some((index, encrypted, rebuilt)) = arrived.pop_front() {
+			// Answers to retransmitted requests may arrive twice, or from another source
+			if index >= end || assembler.received().contains(index) {
+				continue;
+			}
+
+			// Payloads that fail authentication are requested again
+			let data = match source.cipher.decrypt(&encrypted, index, end) {
+				Ok(data) => data,
+				Err(_) => {
+					log_error(&format!("Dropping payload {} that failed authentication", index));
+					continue;
+				}
+			};
+
+			// Payloads that do not match their hash are requested again, like ones that were lost
+			match assembler.write_payload(index, &data) {
+				Ok(_) => {}
+				Err(AssembleError::CorruptBlock { block, payloads }) => {
+					log_error(&format!("Block {} does not match its hash, fetching it again", block));
+					swarm.on_corrupt(payloads);
+					continue;
+				}
+				Err(e) => return Err(TransferError::Assemble(e)),
+			}
+			// Rebuilt payloads say nothing about the round trip time
+			let rtt = if rebuilt {
+				None
+			} else {
+				swarm.rtt_sample(index, Instant::now())
+			};
+			congestion[lane].on_delivered(rtt);
+			swarm.on_received(index);
+			bar.inc(data.len() as u64);
+
+			if let (Some(decoder), false) = (source.repair.as_mut(), rebuilt) {
+				let recovered = decoder.on_payload(index, &encrypted);
+				arrived.extend(recovered.into_iter().map(|(index, data)| (index, data, true)));
 			}
-			Err(_) => {}
 		}
-	}
-	match rx.try_recv() {
-		Ok(payload) => {
-			let key = derive_key(password);
-			let data = decrypt_with_passphrase(&payload.data, &key);
-			file.write(&data).unwrap();
+
+		if stats_shown.elapsed() >= STATS_INTERVAL {
+			bar.set_message(transfer_statistics(sources, &congestion).join("\n"));
+			stats_shown = Instant::now();
 		}
-		Err(_) => {}
 	}
 
-	listener.join().unwrap();
+	bar.finish_and_clear();
+	log_transfer_statistics(sources, &congestion);
+	if swarm.retransmissions() > 0 {
+		log_info(&format!("Requested {} lost payloads again", swarm.retransmissions()));
+	}
+	let recovered: u64 = sources.iter().flat_map(|source| &source.repair).map(RepairDecoder::recovered).sum();
+	if recovered > 0 {
+		log_info(&format!("Rebuilt {} lost payloads from repair symbols", recovered));
+	}
+	Ok(())
 }
 
-pub fn scan() {
+pub fn scan(retry_policy: RetryPolicy, fec: Option<FecParams>, seed: Option<String>) {
 	log_info("Scanning for hosts...");
 
 	let (uploader_channel_tx, uploader_channel_rx) = mpsc::channel::<Option<(UploaderInfo, IpAddr)>>();
@@ -187,19 +568,42 @@ pub fn scan() {
 		scan::scan(uploader_channel_tx);
 	});
 
-	let mut records_set: HashSet<UploaderInfo> = Default::default();
-	let mut records: Vec<(UploaderInfo, IpAddr)> = Default::default();
+	// Announcements are checked against the identities pinned so far, impostors stand out in the list
+	let known_hosts = match KnownHosts::load(&known_hosts_path()) {
+		Ok(known_hosts) => known_hosts,
+		Err(e) => {
+			log_error(&format!("Failed to read {}: {}", known_hosts_path().display(), e));
+			return;
+		}
+	};
+
+	// Hosts announce again and again, a share is known by its content and the host serving it
+	let mut records_set: HashSet<([u8; 32], IpAddr)> = Default::default();
+	let mut records: Vec<(UploaderInfo, IpAddr, Verification)> = Default::default();
 	let mut index: u32 = 0;
 	while let Ok(Some((uploader, address))) = uploader_channel_rx.recv() {
-		records_set.insert(uploader.clone());
-		if records_set.contains(&uploader) {
+		if !records_set.insert((uploader.merkle_root, address)) {
 			continue;
 		}
-		records.push((uploader.clone(), address));
-		log_info(&format!(
-			"{} | Filename: {}, Host: {}",
-			index, uploader.file_name, uploader.name
-		));
+		let verification = check_announcement(&uploader, &known_hosts);
+		records.push((uploader.clone(), address, verification));
+		let mut line = format!(
+			"{} | Filename: {}, Content: {}, Host: {} [{}]",
+			index,
+			uploader.file_name,
+			&blake3::Hash::from(uploader.merkle_root).to_hex()[..16],
+			uploader.name,
+			verification
+		);
+		// Shares the host only serves for a while, or only so often
+		if let Some(limits) = uploader.availability.describe(unix_now()) {
+			line = format!("{}, {}", line, limits);
+		}
+		match verification {
+			Verification::Verified | Verification::New => log_info(&line),
+			Verification::Unverified => log_warning(&line),
+			Verification::Impostor | Verification::Forged => log_error(&line),
+		}
 		index += 1;
 	}
 
@@ -220,160 +624,452 @@ pub fn scan() {
 		.expect("Failed to read line");
 
 	let index: usize = input.trim().parse().unwrap();
-	let selected = records[index].clone();
+	let (host_info, host_ip, verification) = records[index].clone();
+
+	match verification {
+		// Checking the host's identity below refuses impostors, with the details
+		Verification::Verified | Verification::New | Verification::Impostor => {}
+		Verification::Unverified => {
+			log_warning("The host's announcement is not signed, it may not be who it claims to be")
+		}
+		Verification::Forged => {
+			log_error("The host's announcement carries a forged signature, refusing to connect");
+			return;
+		}
+	}
 
-	let host_info = selected.0;
-	let host_ip = selected.1;
+	if !verify_host_identity(&host_info, host_ip) {
+		return;
+	}
 
-	let mut filename = host_info.file_name;
-	let is_public = host_info.public;
+	// Content is identified by its Merkle root, every verified host announcing it joins the download
+	let mut hosts = vec![(host_info.clone(), host_ip)];
+	for (info, address, verification) in &records {
+		let same_content = info.merkle_root == host_info.merkle_root
+			&& info.files_size == host_info.files_size
+			&& info.packaging == host_info.packaging;
+		if !same_content || hosts.iter().any(|(_, known)| known == address) {
+			continue;
+		}
+		match verification {
+			Verification::Verified | Verification::New => {}
+			Verification::Impostor => {
+				log_error(&format!("Not downloading from {}, it is not the host pinned for its name", info.name));
+				continue;
+			}
+			Verification::Unverified | Verification::Forged => {
+				log_warning(&format!("Not downloading from {}, its announcement is not signed", info.name));
+				continue;
+			}
+		}
+		if verify_host_identity(info, *address) {
+			hosts.push((info.clone(), *address));
+		}
+	}
+	if hosts.len() > 1 {
+		log_info(&format!("{} hosts share this content, downloading from all of them", hosts.len()));
+	}
+
+	// Clients pin identities by name, a seed under a host's name would look like an impersonation
+	if let Some(name) = &seed {
+		if hosts.iter().any(|(info, _)| &info.name == name) {
+			log_error(&format!("{} already shares this content, seed it under another name", name));
+			return;
+		}
+	}
 
+	let socket = match UdpSocket::bind("0.0.0.0:0") {
+		Ok(socket) => socket,
+		Err(e) => {
+			log_error(&format!("Couldn't bind to address: {}", e));
+			return;
+		}
+	};
+
+	let mut filename = host_info.file_name.clone();
 	if PackagingType::Tarred == host_info.packaging {
 		filename = format!("{}.tar.gz", filename);
 	}
 
-	if is_public == false {
-		let host_public_key_string = host_info.public_key.unwrap();
-		let host_public_key = public_key_from_string(host_public_key_string).unwrap();
-
+	// Public shares have no passphrase, their logins are authenticated by the host's identity alone
+	let password = if hosts.iter().all(|(info, _)| info.public) {
+		None
+	} else {
 		log_info("password: ");
 		let mut password = String::new();
 		io::stdin()
 			.read_line(&mut password)
 			.expect("Failed to read line");
 
-		let password = password.trim();
+		Some(password.trim().to_string())
+	};
 
-		let mut rng = OsRng;
+	let candidates: Vec<Candidate> = hosts
+		.into_iter()
+		.filter_map(|(info, address)| prepare_candidate(&socket, info, address, password.as_deref()))
+		.collect();
 
-		let encrypted_password = host_public_key
-			.encrypt(&mut rng, Pkcs1v15Encrypt, password.as_bytes())
-			.unwrap();
+	// Every host has to cut the file into the same payloads, the smallest path decides their size
+	let Some(payload_size) = candidates.iter().map(|candidate| candidate.payload_size).min() else {
+		return;
+	};
 
-		// Get the payload count from the first payload
-		
-		let first_payload = request_and_await_payload(host_ip, encrypted_password.clone(), 0);
+	let mut sessions: Vec<(Candidate, LoginSession)> = Vec::new();
+	for candidate in candidates {
+		let host_public_key = candidate.info.public_key.clone().unwrap_or_default();
+		match login(&socket, candidate.addr, candidate.info.share_id, &candidate.share_key, &host_public_key, payload_size, fec) {
+			Ok(session) if session.payload_size == payload_size => sessions.push((candidate, session)),
+			Ok(session) => log_warning(&format!(
+				"Not downloading from {}, it sends {} byte payloads instead of {}",
+				candidate.info.name, session.payload_size, payload_size
+			)),
+			Err(e) => log_error(&format!("Failed to log in to {}: {}", candidate.addr.ip(), e)),
+		}
+	}
+	if sessions.is_empty() {
+		return;
+	}
+	log_info(&format!("Receiving {} byte payloads", payload_size));
 
-		if !first_payload.success {
-			log_error("Failed to receive payload info from host");
+	// Get the payload count from the first payload
+	let (first_candidate, first_session) = &sessions[0];
+	let first_payload = match request_first_payload(&socket, first_candidate.addr, first_session.id, first_candidate.info.share_id) {
+		Ok(payload) => payload,
+		Err(e) => {
+			log_error(&format!("Failed to receive payload info from host: {}", e));
 			return;
 		}
+	};
 
-		let payload_count = first_payload.payload_count;
+	// Downloads of the same content pick up where they were interrupted, whichever hosts share it
+	let identity = DownloadIdentity {
+		merkle_root: host_info.merkle_root,
+		file_size: host_info.files_size,
+	};
+	let mut assembler = match FileAssembler::open(Path::new(&filename), identity, payload_size) {
+		Ok(assembler) => assembler,
+		Err(e) => {
+			log_error(&format!("Failed to create {}.part: {}", filename, e));
+			return;
+		}
+	};
+	if assembler.received().count() > 0 {
+		log_info(&format!(
+			"Resuming, {} of {} payloads were downloaded before",
+			assembler.received().count(),
+			assembler.payload_count()
+		));
+	}
+
+	let payload_count = first_payload.payload_count;
+	if payload_count != assembler.payload_count() {
+		log_error(&format!(
+			"The host sends {} payloads, but the announced file size needs {}",
+			payload_count,
+			assembler.payload_count()
+		));
+		return;
+	}
 
-		let mut file = OpenOptions::new()
-			.write(true)
-			.create(true)
-			.open(filename.clone())
-			.unwrap();
+	// Lost payloads are rebuilt from repair symbols if a host agreed to send them
+	let last_size = assembler.file_size() - u64::from(payload_count.saturating_sub(1)) * u64::from(payload_size);
+	let mut sources: Vec<Source> = sessions
+		.into_iter()
+		.map(|(candidate, session)| {
+			if let Some(params) = session.fec {
+				log_info(&format!(
+					"{} sends {} repair symbols per {} payloads",
+					candidate.info.name, params.repair_symbols, params.group_size
+				));
+			}
+			Source {
+				name: candidate.info.name,
+				addr: candidate.addr,
+				session_id: session.id,
+				share_id: candidate.info.share_id,
+				// The session key is only expanded once for the whole download
+				cipher: PayloadCipher::new(&session.key, candidate.info.nonce_seed),
+				repair: session.fec.map(|params| {
+					RepairDecoder::new(
+						params,
+						payload_size as usize + AUTHENTICATION_TAG_SIZE,
+						payload_count,
+						last_size as usize + AUTHENTICATION_TAG_SIZE,
+					)
+				}),
+			}
+		})
+		.collect();
 
-		file.write_all(b"").unwrap();
-		drop(file);
+	// Every block is checked against its hash once its payloads arrived, the hashes against the root
+	let block_count = assembler.block_count();
+	let hashes = match fetch_block_hashes(&socket, sources[0].addr, sources[0].session_id, sources[0].share_id, block_count) {
+		Ok(hashes) => hashes,
+		Err(e) => {
+			log_error(&format!("Failed to receive the block hashes: {}", e));
+			return;
+		}
+	};
+	if let Err(e) = assembler.verify_blocks(hashes) {
+		log_error(&format!("The block hashes from {} are wrong: {}", sources[0].name, e));
+		return;
+	}
 
-		let mut file: fs::File = OpenOptions::new()
-			.write(true)
-			.create(true)
-			.append(true)
-			.open(filename)
-			.unwrap();
+	let data = match sources[0].cipher.decrypt(&first_payload.data, first_payload.index, payload_count) {
+		Ok(data) => data,
+		Err(_) => {
+			log_error("Failed to decrypt the first payload");
+			return;
+		}
+	};
+	// A corrupt block is fetched again with the rest of the download
+	match assembler.write_payload(first_payload.index, &data) {
+		Ok(_) => {}
+		Err(AssembleError::CorruptBlock { block, .. }) => {
+			log_error(&format!("Block {} does not match its hash, fetching it again", block))
+		}
+		Err(e) => {
+			log_error(&format!("Failed to write the first payload: {}", e));
+			return;
+		}
+	}
+	if let Some(decoder) = sources[0].repair.as_mut() {
+		decoder.on_payload(first_payload.index, &first_payload.data);
+	}
 
-		let encrypted_data = &first_payload.data;
+	let result = match get_payloads_via_pipeline(&socket, &mut sources, &mut assembler, retry_policy) {
+		Ok(()) => assembler.finish().map_err(TransferError::Assemble),
+		Err(e) => {
+			// Keep what arrived for the next attempt
+			if let Err(e) = assembler.save_progress() {
+				log_error(&format!("Failed to save the download progress: {}", e));
+			}
+			Err(e)
+		}
+	};
+	let downloaded = match result {
+		Ok(()) => {
+			log_success(&format!("Downloaded {}", filename));
+			// Hosts count finished downloads against their download limits
+			for source in &sources {
+				let finished = ReditPacket::DownloadFinished(DownloadFinished {
+					session_id: source.session_id,
+					share_id: source.share_id,
+				});
+				let _ = socket.send_to(&finished.encode(), source.addr);
+			}
+			true
+		}
+		Err(e) => {
+			log_error(&format!("Failed to download {}: {}", filename, e));
+			false
+		}
+	};
 
-		let key = derive_key(password);
-		let data = decrypt_with_passphrase(encrypted_data, &key);
-		file.write_all(&data).unwrap();
+	recipient.join().unwrap();
 
-		get_payloads_via_pipeline(
-			host_ip,
-			encrypted_password.clone(),
-			1,
-			payload_count,
-			0,
-			host_info.files_size.try_into().unwrap(),
-			file,
-			password,
-		);
-	} else {
-		log_info("Cannot connect to a private host");
+	// Share the verified download on, so others need not fetch it from the original hosts
+	if let (true, Some(name)) = (downloaded, seed) {
+		server::seed(Path::new(&filename), &host_info, name, password.as_deref().unwrap_or(""));
 	}
+}
 
-	recipient.join().unwrap()
+// A host that answered our handshake, with what to log in to it with
+struct Candidate {
+	info: UploaderInfo,
+	addr: SocketAddr,
+	share_key: [u8; 32],
+	// Largest payload that reaches us from the host in a single datagram
+	payload_size: u32,
 }
 
-pub fn request_and_await_payload(
+// Exchange capabilities with a host and measure the path to it, logs why a host cannot be used
+fn prepare_candidate(
+	socket: &UdpSocket,
+	info: UploaderInfo,
 	host_ip: IpAddr,
-	encrypted_password: Vec<u8>,
-	chunk: u32,
-) -> Payload {
-	let socket = UdpSocket::bind("0.0.0.0:6970")
-		.map_err(|e| e.to_string())
-		.unwrap();
-
-	let host_addr: SocketAddr = SocketAddr::new(host_ip, 6969);
-
-	request_payload(
-		socket.try_clone().unwrap(),
-		host_addr,
-		encrypted_password,
-		chunk,
-	);
+	password: Option<&str>,
+) -> Option<Candidate> {
+	let host_addr = SocketAddr::new(host_ip, PORT);
 
-	await_payload(socket, host_addr)
-}
+	let capabilities = match handshake(socket, host_addr) {
+		Ok(capabilities) => capabilities,
+		Err(e) => {
+			log_error(&format!("Cannot connect to {}: {}", host_ip, e));
+			return None;
+		}
+	};
+
+	if !capabilities.packaging.contains(&info.packaging) {
+		log_error(&format!("{} shares content in a packaging this build cannot handle", info.name));
+		return None;
+	}
 
-pub fn request_payload(
-	socket: UdpSocket,
-	uploader_addr: SocketAddr,
-	hashed_password: Vec<u8>,
-	payload_index: u32,
-) {
-	let request_payload = RequestPayload {
-		hashed_password,
-		payload_index,
+	if !info.kdf_params.is_acceptable() {
+		log_error(&format!("{} asks for unreasonable key derivation parameters", info.name));
+		return None;
+	}
+
+	let share_key = match (info.public, password) {
+		(true, _) => PUBLIC_SHARE_KEY,
+		(false, Some(password)) => {
+			let salt = info.hashed_connection_salt.clone().unwrap_or_default();
+			match derive_key(password, &salt, &info.kdf_params) {
+				Ok(key) => key,
+				Err(e) => {
+					log_error(&format!("Failed to derive the share key: {}", e));
+					return None;
+				}
+			}
+		}
+		(false, None) => return None,
 	};
 
-	let payload = bincode::serialize(&ReditPacket::RequestPayload(request_payload)).unwrap();
+	// Payloads are sized to reach us in a single unfragmented datagram
+	let datagram_size = discover_datagram_size(socket, host_addr, capabilities.max_payload_size);
+	log_info(&format!("Path to {} carries {} byte datagrams", info.name, datagram_size));
 
-	socket
-		.send_to(&payload, uploader_addr)
-		.map_err(|e| e.to_string())
-		.unwrap();
+	Some(Candidate {
+		payload_size: payload_size_for(datagram_size).min(capabilities.max_payload_size),
+		info,
+		addr: host_addr,
+		share_key,
+	})
 }
 
-// Wait for a payload
-pub fn await_payload(socket: UdpSocket, uploader_addr: SocketAddr) -> Payload {
-	let mut buf = [0; 49152];
+// Request the first payload, it tells how many payloads the share has
+fn request_first_payload(
+	socket: &UdpSocket,
+	host_addr: SocketAddr,
+	session_id: u64,
+	share_id: u32,
+) -> Result<Payload, ConnectError> {
+	let request = ReditPacket::RequestPayload(RequestPayload {
+		session_id,
+		share_id,
+		payload_index: 0,
+	});
 
-	loop {
-		let (amt, src) = match socket.recv_from(&mut buf) {
-			Ok((amt, src)) => (amt, src),
-			Err(e) => {
-				log_error(&format!("Failed to receive packet: {}", e));
-				continue;
-			}
-		};
+	exchange(socket, host_addr, &request, |packet| match packet {
+		ReditPacket::Payload(payload) if !payload.success => Some(Err(ConnectError::SessionRejected)),
+		ReditPacket::Payload(payload) if payload.index == 0 => Some(Ok(payload)),
+		_ => None,
+	})
+}
 
-		let packet_data = &buf[..amt];
-		let packet: ReditPacket = match bincode::deserialize(packet_data) {
-			Ok(data) => data,
-			Err(e) => {
-				log_error(&format!("Received a corrupt packet: {:?}", e));
-				continue;
-			}
+// Fetch the hashes of the blocks of the file, as many per answer as the host sends
+fn fetch_block_hashes(
+	socket: &UdpSocket,
+	host_addr: SocketAddr,
+	session_id: u64,
+	share_id: u32,
+	block_count: u32,
+) -> Result<Vec<[u8; 32]>, ConnectError> {
+	let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(block_count as usize);
+	while (hashes.len() as u32) < block_count {
+		let start = hashes.len() as u32;
+		let request = ReditPacket::RequestNodeHashes(RequestNodeHashes {
+			session_id,
+			share_id,
+			start,
+			count: block_count - start,
+		});
+		let batch = exchange(socket, host_addr, &request, |packet| match packet {
+			ReditPacket::NodeHashes(answer) if answer.start == start => Some(Ok(answer.hashes)),
+			_ => None,
+		})?;
+
+		if batch.is_empty() {
+			return Err(ConnectError::MissingHashes);
+		}
+		hashes.extend(batch.into_iter().take((block_count - start) as usize));
+	}
+	Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::encryption::{generate_private_key, generate_public_key, public_key_to_string, sign};
+	use crate::retransmit::ReceivedBitmap;
+	use crate::test_utils::{announcement, TempDir};
+
+	#[test]
+	fn test_verify_announcement() {
+		let identity = generate_private_key();
+		let mut info = announcement();
+		assert_eq!(verify_announcement(&info), Verification::Unverified);
+
+		info.public_key = Some(public_key_to_string(&generate_public_key(identity.clone())));
+		info.signature = sign(&identity, &info.signed_bytes());
+		assert_eq!(verify_announcement(&info), Verification::Verified);
+
+		// Spoofing the name invalidates the signature
+		info.name = "impostor".to_string();
+		assert_eq!(verify_announcement(&info), Verification::Forged);
+	}
+
+	#[test]
+	fn test_impostors_are_not_verified() {
+		let dir = TempDir::new("known-hosts");
+		let mut known_hosts = KnownHosts::load(&dir.join("known_hosts")).unwrap();
+
+		let signed = |identity: &rsa::RsaPrivateKey| {
+			let mut info = announcement();
+			info.public_key = Some(public_key_to_string(&generate_public_key(identity.clone())));
+			info.signature = sign(identity, &info.signed_bytes());
+			info
 		};
+		let host = signed(&generate_private_key());
+		assert_eq!(check_announcement(&host, &known_hosts), Verification::New);
+		assert_eq!(check_announcement(&announcement(), &known_hosts), Verification::Unverified);
 
-		match packet {
-			ReditPacket::Payload(payload) => {
-				// Make sure it's from the right person
-				if src.ip() == uploader_addr.ip() {
-					return payload;
-				}
-			}
-			unexpected => {
-				log_error(&format!("Received an unexpected packet: {:?}", unexpected));
-				continue;
+		let address: IpAddr = "192.168.1.20".parse().unwrap();
+		known_hosts.pin(&host.name, address, host.public_key.as_deref().unwrap()).unwrap();
+		assert_eq!(check_announcement(&host, &known_hosts), Verification::Verified);
+
+		// Another identity under the same name signs its announcement just as well
+		let impostor = signed(&generate_private_key());
+		assert_eq!(verify_announcement(&impostor), Verification::Verified);
+		assert_eq!(check_announcement(&impostor, &known_hosts), Verification::Impostor);
+	}
+
+	#[test]
+	fn test_consecutive_runs() {
+		assert_eq!(consecutive_runs(&[]), vec![]);
+		assert_eq!(consecutive_runs(&[3, 4, 5, 9, 12, 13]), vec![(3, 3), (9, 1), (12, 2)]);
+		// Retransmissions come first and are out of order
+		assert_eq!(consecutive_runs(&[7, 2, 10, 11]), vec![(7, 1), (2, 1), (10, 2)]);
+
+		// Runs are cut where a host would cut them short
+		let long: Vec<u32> = (0..2500).collect();
+		assert_eq!(consecutive_runs(&long), vec![(0, 1024), (1024, 1024), (2048, 452)]);
+	}
+
+	#[test]
+	fn test_range_requests_cover_many_payloads() {
+		const PAYLOADS: u32 = 100_000;
+		let mut swarm = Swarm::new(PAYLOADS, 1, RetryPolicy::default(), &ReceivedBitmap::new(PAYLOADS));
+		let mut congestion = CongestionControl::new(Duration::from_millis(250));
+		let now = Instant::now();
+
+		// A lossless transfer, payloads arrive one at a time in the order they were asked for
+		let mut outstanding = VecDeque::new();
+		let mut requests = 0;
+		loop {
+			for (start, count) in range_requests(&mut swarm, 0, &congestion, now).unwrap() {
+				assert!(count <= MAX_RANGE_LENGTH);
+				requests += 1;
+				outstanding.extend(start..start + count);
 			}
+			let Some(index) = outstanding.pop_front() else {
+				break;
+			};
+			swarm.on_received(index);
+			congestion.on_delivered(Some(Duration::from_millis(10)));
 		}
+
+		assert_eq!(congestion.stats().delivered, u64::from(PAYLOADS));
+		assert!(requests < PAYLOADS / 100, "{} range requests for {} payloads", requests, PAYLOADS);
 	}
 }
-
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Bounds of the number of payload requests kept outstanding
+const MIN_WINDOW: f64 = 2.0;
+const MAX_WINDOW: f64 = 4096.0;
+const INITIAL_WINDOW: f64 = 4.0;
+
+// Bounds of the retransmission timeout
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(4);
+
+// Snapshot of a transfer for diagnostics
+#[derive(Debug, Clone, Copy)]
+pub struct TransferStats {
+	pub srtt: Option<Duration>,
+	pub rttvar: Duration,
+	pub rto: Duration,
+	pub window: usize,
+	pub delivered: u64,
+	pub lost: u64,
+}
+
+impl TransferStats {
+	pub fn loss_rate(&self) -> f64 {
+		let total = self.delivered + self.lost;
+		if total == 0 {
+			return 0.0;
+		}
+		self.lost as f64 / total as f64
+	}
+}
+
+impl fmt::Display for TransferStats {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.srtt {
+			Some(srtt) => write!(
+				f,
+				"rtt {:.1}ms ±{:.1}ms",
+				srtt.as_secs_f64() * 1000.0,
+				self.rttvar.as_secs_f64() * 1000.0
+			)?,
+			None => write!(f, "rtt -")?,
+		}
+		write!(
+			f,
+			", timeout {}ms, window {}, loss {:.1}% ({} of {})",
+			self.rto.as_millis(),
+			self.window,
+			self.loss_rate() * 100.0,
+			self.lost,
+			self.delivered + self.lost
+		)
+	}
+}
+
+// Additive increase, multiplicative decrease of the request window. The window grows
+// exponentially until the first loss, then by one payload per round trip, and halves
+// at most once per round trip when requests time out. Round trip times are smoothed
+// as in RFC 6298 to derive the retransmission timeout.
+pub struct CongestionControl {
+	window: f64,
+	slow_start_threshold: f64,
+	srtt: Option<Duration>,
+	rttvar: Duration,
+	min_rtt: Option<Duration>,
+	initial_rto: Duration,
+	last_decrease: Option<Instant>,
+	delivered: u64,
+	lost: u64,
+}
+
+impl CongestionControl {
+	pub fn new(initial_rto: Duration) -> Self {
+		CongestionControl {
+			window: INITIAL_WINDOW,
+			slow_start_threshold: MAX_WINDOW,
+			srtt: None,
+			rttvar: Duration::ZERO,
+			min_rtt: None,
+			initial_rto,
+			last_decrease: None,
+			delivered: 0,
+			lost: 0,
+		}
+	}
+
+	// A requested payload arrived, `rtt` is only given for payloads that were requested once
+	pub fn on_delivered(&mut self, rtt: Option<Duration>) {
+		self.delivered += 1;
+
+		if let Some(rtt) = rtt {
+			self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+			match self.srtt {
+				None => {
+					self.srtt = Some(rtt);
+					self.rttvar = rtt / 2;
+				}
+				Some(srtt) => {
+					let deviation = srtt.abs_diff(rtt);
+					self.rttvar = (self.rttvar * 3 + deviation) / 4;
+					self.srtt = Some((srtt * 7 + rtt) / 8);
+				}
+			}
+		}
+
+		if self.window < self.slow_start_threshold {
+			self.window += 1.0;
+		} else {
+			self.window += 1.0 / self.window;
+		}
+		self.window = self.window.min(MAX_WINDOW);
+	}
+
+	// Requests timed out, the window shrinks once per round trip however many were lost
+	pub fn on_lost(&mut self, count: u32, now: Instant) {
+		if count == 0 {
+			return;
+		}
+		self.lost += u64::from(count);
+
+		let round_trip = self.srtt.unwrap_or(self.initial_rto);
+		if let Some(last_decrease) = self.last_decrease {
+			if now.saturating_duration_since(last_decrease) < round_trip {
+				return;
+			}
+		}
+
+		self.slow_start_threshold = (self.window / 2.0).max(MIN_WINDOW);
+		self.window = self.slow_start_threshold;
+		self.last_decrease = Some(now);
+	}
+
+	pub fn window(&self) -> usize {
+		self.window as usize
+	}
+
+	// Time between payloads that spreads a window over the shortest round trip seen,
+	// the host paces range requests with it instead of sending them in one burst
+	pub fn pacing_interval(&self) -> Duration {
+		match self.min_rtt {
+			Some(min_rtt) => min_rtt / self.window().max(1) as u32,
+			None => Duration::ZERO,
+		}
+	}
+
+	// Time a request is given to be answered before it is considered lost
+	pub fn rto(&self) -> Duration {
+		match self.srtt {
+			Some(srtt) => (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO),
+			None => self.initial_rto,
+		}
+	}
+
+	pub fn stats(&self) -> TransferStats {
+		TransferStats {
+			srtt: self.srtt,
+			rttvar: self.rttvar,
+			rto: self.rto(),
+			window: self.window(),
+			delivered: self.delivered,
+			lost: self.lost,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_window_grows_and_halves() {
+		let mut congestion = CongestionControl::new(Duration::from_millis(250));
+		let start = Instant::now();
+
+		// Slow start grows the window by one per delivered payload
+		for _ in 0..12 {
+			congestion.on_delivered(Some(Duration::from_millis(10)));
+		}
+		assert_eq!(congestion.window(), 16);
+
+		// A burst of losses in the same round trip halves the window once
+		congestion.on_lost(3, start);
+		congestion.on_lost(2, start + Duration::from_millis(1));
+		assert_eq!(congestion.window(), 8);
+
+		// Congestion avoidance grows it by about one per round trip
+		for _ in 0..8 {
+			congestion.on_delivered(Some(Duration::from_millis(10)));
+		}
+		assert_eq!(congestion.window(), 8);
+		congestion.on_delivered(Some(Duration::from_millis(10)));
+		assert_eq!(congestion.window(), 9);
+
+		// Losses in a later round trip halve it again, but never below the minimum
+		for i in 1..10 {
+			congestion.on_lost(1, start + Duration::from_secs(i));
+		}
+		assert_eq!(congestion.window(), MIN_WINDOW as usize);
+
+		let stats = congestion.stats();
+		assert_eq!(stats.delivered, 21);
+		assert_eq!(stats.lost, 14);
+	}
+
+	#[test]
+	fn test_rto() {
+		let mut congestion = CongestionControl::new(Duration::from_millis(250));
+		assert_eq!(congestion.rto(), Duration::from_millis(250));
+
+		congestion.on_delivered(Some(Duration::from_millis(40)));
+		assert_eq!(congestion.stats().srtt, Some(Duration::from_millis(40)));
+		assert_eq!(congestion.rto(), Duration::from_millis(120));
+
+		// A steady round trip time narrows the timeout towards it
+		for _ in 0..50 {
+			congestion.on_delivered(Some(Duration::from_millis(40)));
+		}
+		assert!(congestion.rto() < Duration::from_millis(45));
+
+		// Retransmitted payloads give no samples
+		congestion.on_delivered(None);
+		assert_eq!(congestion.stats().srtt, Some(Duration::from_millis(40)));
+	}
+
+	#[test]
+	fn test_pacing_interval() {
+		let mut congestion = CongestionControl::new(Duration::from_millis(250));
+		assert_eq!(congestion.pacing_interval(), Duration::ZERO, "Nothing to pace by before a round trip");
+
+		congestion.on_delivered(Some(Duration::from_millis(50)));
+		congestion.on_delivered(Some(Duration::from_millis(20)));
+		congestion.on_delivered(Some(Duration::from_millis(90)));
+		assert_eq!(congestion.window(), 7);
+		assert_eq!(congestion.pacing_interval(), Duration::from_millis(20) / 7);
+	}
+}
@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// The hosted file, opened once for the lifetime of the share. Reads are positioned, so
+// workers read from it at the same time without sharing a cursor.
+pub struct ContentReader {
+	file: File,
+	size: u64,
+	// Reads share the file's cursor where positioned reads are not available
+	#[cfg(not(any(unix, windows)))]
+	cursor: std::sync::Mutex<()>,
+}
+
+#[cfg(unix)]
+fn read_exact_at(reader: &ContentReader, buf: &mut [u8], offset: u64) -> io::Result<()> {
+	use std::os::unix::fs::FileExt;
+	reader.file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(reader: &ContentReader, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+	use std::os::windows::fs::FileExt;
+	while !buf.is_empty() {
+		match reader.file.seek_read(buf, offset) {
+			Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+			Ok(read) => {
+				buf = &mut buf[read..];
+				offset += read as u64;
+			}
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(reader: &ContentReader, buf: &mut [u8], offset: u64) -> io::Result<()> {
+	use std::io::{Read, Seek, SeekFrom};
+	let _cursor = reader.cursor.lock().unwrap();
+	let mut file = &reader.file;
+	file.seek(SeekFrom::Start(offset))?;
+	file.read_exact(buf)
+}
+
+impl ContentReader {
+	pub fn open(path: &Path) -> io::Result<Self> {
+		let file = File::open(path)?;
+		let size = file.metadata()?.len();
+		Ok(ContentReader {
+			file,
+			size,
+			#[cfg(not(any(unix, windows)))]
+			cursor: std::sync::Mutex::new(()),
+		})
+	}
+
+	pub fn size(&self) -> u64 {
+		self.size
+	}
+
+	// Up to `len` bytes from `start` on, the last chunk of the file is shorter
+	pub fn read(&self, start: u64, len: usize) -> io::Result<Vec<u8>> {
+		if start >= self.size {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "read past the end of the file"));
+		}
+
+		let len = (self.size - start).min(len as u64) as usize;
+		let mut buffer = vec![0; len];
+		read_exact_at(self, &mut buffer, start).map_err(|e| match e.kind() {
+			io::ErrorKind::UnexpectedEof => io::Error::new(e.kind(), "the file shrank since it was shared"),
+			_ => e,
+		})?;
+		Ok(buffer)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+	use std::fs;
+	use std::io::{Read, Seek, SeekFrom};
+	use std::time::Instant;
+
+	#[test]
+	fn test_partial_last_chunk() {
+		let data: Vec<u8> = (0..2500u32).map(|i| (i * 3) as u8).collect();
+		let dir = TempDir::new("content");
+		let path = dir.file("shared", &data);
+		let reader = ContentReader::open(&path).unwrap();
+
+		assert_eq!(reader.size(), 2500);
+		assert_eq!(reader.read(1024, 1024).unwrap(), &data[1024..2048]);
+		assert_eq!(reader.read(2048, 1024).unwrap(), &data[2048..], "The last chunk is short");
+		assert!(reader.read(3072, 1024).is_err());
+		assert!(reader.read(2500, 1024).is_err());
+
+		// A file that shrank while shared is an error, not a panic
+		fs::write(&path, &data[..2000]).unwrap();
+		assert_eq!(reader.read(2048, 1024).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+	}
+
+	// Compares reading payloads through the open file with opening the file for every one.
+	// Run with `cargo test --release bench_payload_reads -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_payload_reads() {
+		const PAYLOAD_SIZE: usize = 8192;
+		let data = vec![7u8; 64 << 20];
+		let dir = TempDir::new("content");
+		let path = dir.file("shared", &data);
+
+		let start = Instant::now();
+		for offset in (0..data.len()).step_by(PAYLOAD_SIZE) {
+			let mut file = File::open(&path).unwrap();
+			file.seek(SeekFrom::Start(offset as u64)).unwrap();
+			let mut buffer = vec![0; PAYLOAD_SIZE];
+			file.read_exact(&mut buffer).unwrap();
+		}
+		let reopened = start.elapsed();
+
+		let reader = ContentReader::open(&path).unwrap();
+		let start = Instant::now();
+		for offset in (0..data.len()).step_by(PAYLOAD_SIZE) {
+			reader.read(offset as u64, PAYLOAD_SIZE).unwrap();
+		}
+		let kept_open = start.elapsed();
+
+		let mib_per_s = |elapsed: std::time::Duration| (data.len() >> 20) as f64 / elapsed.as_secs_f64();
+		println!("Opened for every payload: {:.0} MiB/s", mib_per_s(reopened));
+		println!("Kept open: {:.0} MiB/s", mib_per_s(kept_open));
+	}
+}
@@ -0,0 +1,230 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::logger::{log_error, log_info, log_success};
+use crate::policy::{unix_now, Availability};
+use crate::types::CatalogEntry;
+use crate::utils::redit_dir;
+
+// Largest request or response a control connection carries
+const MAX_MESSAGE_SIZE: u64 = 1 << 20;
+// Requests are answered one at a time, a client that does not finish sending is given up on
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What `redit share` and `redit status` ask a host daemon to do
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ControlRequest {
+	AddShare { path: PathBuf, availability: Availability },
+	RemoveShare { share_id: u32 },
+	ListShares,
+	RotatePassphrase { passphrase: String },
+	Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum ControlResponse {
+	ShareAdded { share_id: u32 },
+	ShareRemoved,
+	Shares(Vec<CatalogEntry>),
+	PassphraseRotated,
+	Status(HostStatus),
+	Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HostStatus {
+	pub name: String,
+	pub identity: String,
+	pub shares: usize,
+	pub clients: Vec<ClientStatus>,
+}
+
+// A client that logged in, or is logging in, to one of the shares
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ClientStatus {
+	pub addr: SocketAddr,
+	pub share_id: u32,
+	pub confirmed: bool,
+	pub idle_secs: u64,
+}
+
+// The control socket of the daemon hosting from this Redit directory, in a directory of its own
+// that only the user may enter
+pub fn socket_path() -> PathBuf {
+	redit_dir().join("control").join("control.sock")
+}
+
+// Connections carry one message each way, each ends where the sender shuts its half down
+fn read_message<T: DeserializeOwned>(stream: &mut impl Read) -> io::Result<T> {
+	let mut buf = Vec::new();
+	stream.take(MAX_MESSAGE_SIZE).read_to_end(&mut buf)?;
+	bincode::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<T: Serialize>(stream: &mut impl Write, message: &T) -> io::Result<()> {
+	let buf = bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	stream.write_all(&buf)
+}
+
+#[cfg(unix)]
+pub struct ControlSocket {
+	listener: std::os::unix::net::UnixListener,
+}
+
+#[cfg(unix)]
+impl ControlSocket {
+	pub fn bind(path: &Path) -> io::Result<Self> {
+		use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+		use std::os::unix::net::{UnixListener, UnixStream};
+
+		// Only the user the daemon runs as may control it. The socket is created with whatever
+		// the umask allows, so it is made in a directory nobody else can reach it through.
+		let dir = path
+			.parent()
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the socket path has no directory"))?;
+		std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+		std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+
+		// A socket left behind by a daemon that ended is replaced, one that answers is in use
+		if path.exists() {
+			if UnixStream::connect(path).is_ok() {
+				return Err(io::Error::new(io::ErrorKind::AddrInUse, "another daemon is running"));
+			}
+			std::fs::remove_file(path)?;
+		}
+
+		let listener = UnixListener::bind(path)?;
+		std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+		Ok(ControlSocket { listener })
+	}
+
+	// Answer requests one at a time until the process ends
+	pub fn serve(self, handler: impl Fn(ControlRequest) -> ControlResponse) {
+		for stream in self.listener.incoming() {
+			let mut stream = match stream {
+				Ok(stream) => stream,
+				Err(e) => {
+					log_error(&format!("Failed to accept a control connection: {}", e));
+					continue;
+				}
+			};
+
+			if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+				log_error(&format!("Failed to set up a control connection: {}", e));
+				continue;
+			}
+			let response = match read_message(&mut stream) {
+				Ok(request) => handler(request),
+				Err(e) => ControlResponse::Failed(format!("Malformed request: {}", e)),
+			};
+			if let Err(e) = write_message(&mut stream, &response) {
+				log_error(&format!("Failed to answer a control request: {}", e));
+			}
+		}
+	}
+}
+
+#[cfg(unix)]
+fn send(path: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+	let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+	write_message(&mut stream, request)?;
+	stream.shutdown(std::net::Shutdown::Write)?;
+	read_message(&mut stream)
+}
+
+#[cfg(not(unix))]
+pub struct ControlSocket;
+
+#[cfg(not(unix))]
+impl ControlSocket {
+	pub fn bind(_path: &Path) -> io::Result<Self> {
+		Err(io::Error::new(io::ErrorKind::Unsupported, "control sockets need Unix-domain sockets"))
+	}
+
+	pub fn serve(self, _handler: impl Fn(ControlRequest) -> ControlResponse) {}
+}
+
+#[cfg(not(unix))]
+fn send(_path: &Path, _request: &ControlRequest) -> io::Result<ControlResponse> {
+	Err(io::Error::new(io::ErrorKind::Unsupported, "control sockets need Unix-domain sockets"))
+}
+
+// Send a request to the daemon and report its answer
+pub fn run(request: ControlRequest) {
+	let path = socket_path();
+	let response = match send(&path, &request) {
+		Ok(response) => response,
+		Err(e) => {
+			log_error(&format!("Failed to reach the daemon at {}: {}", path.display(), e));
+			log_error("Start one with `redit host --daemon`");
+			return;
+		}
+	};
+
+	match response {
+		ControlResponse::ShareAdded { share_id } => log_success(&format!("Added as share {}", share_id)),
+		ControlResponse::ShareRemoved => log_success("Removed the share"),
+		ControlResponse::Shares(entries) if entries.is_empty() => log_info("Nothing is shared"),
+		ControlResponse::Shares(entries) => {
+			for entry in entries {
+				let mut line = format!("{} | {}, {} bytes", entry.share_id, entry.name, entry.files_size);
+				if let Some(limits) = entry.availability.describe(unix_now()) {
+					line = format!("{}, {}", line, limits);
+				}
+				log_info(&line);
+			}
+		}
+		ControlResponse::PassphraseRotated => log_success("The shares have a new passphrase"),
+		ControlResponse::Status(status) => {
+			log_info(&format!("Hosting {} shares as {} ({})", status.shares, status.name, status.identity));
+			if status.clients.is_empty() {
+				log_info("No clients are logged in");
+			}
+			for client in status.clients {
+				let state = if client.confirmed { "logged in" } else { "logging in" };
+				log_info(&format!(
+					"{} | share {}, {}, idle for {}s",
+					client.addr, client.share_id, state, client.idle_secs
+				));
+			}
+		}
+		ControlResponse::Failed(reason) => log_error(&reason),
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+	use std::os::unix::fs::PermissionsExt;
+	use std::thread;
+
+	#[test]
+	fn test_requests_reach_the_daemon() {
+		let temp = TempDir::new("control");
+		// The socket's directory is created private
+		let dir = temp.join("control");
+		let path = dir.join("control.sock");
+		let control = ControlSocket::bind(&path).unwrap();
+		assert!(ControlSocket::bind(&path).is_err(), "A second daemon must not take over the socket");
+		let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+		assert_eq!(mode & 0o777, 0o700, "Nobody else may reach the socket");
+
+		thread::spawn(move || {
+			control.serve(|request| match request {
+				ControlRequest::RemoveShare { share_id } => ControlResponse::Failed(format!("There is no share {}", share_id)),
+				_ => ControlResponse::ShareRemoved,
+			})
+		});
+
+		// A client that never finishes its request only holds up the others for a while
+		let _stalled = std::os::unix::net::UnixStream::connect(&path).unwrap();
+		let response = send(&path, &ControlRequest::RemoveShare { share_id: 7 }).unwrap();
+		assert_eq!(response, ControlResponse::Failed("There is no share 7".to_string()));
+		assert_eq!(send(&path, &ControlRequest::ListShares).unwrap(), ControlResponse::ShareRemoved);
+	}
+}
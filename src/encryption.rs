@@ -1,5 +1,5 @@
 use aes_gcm::{
-	aead::{Aead, KeyInit},
+	aead::{Aead, KeyInit, Payload as AeadPayload},
 	Aes256Gcm, Nonce,
 };
 use argon2::{password_hash::SaltString, Argon2, Params, PasswordHasher};
@@ -9,10 +9,18 @@ use base64::{
 	Engine as _,
 };
 
-use crate::words::WORDS;
+use crate::types::KdfParams;
 use itertools::Itertools;
-use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding};
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::sha2::Sha256;
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
 use rsa::{RsaPrivateKey, RsaPublicKey};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::fs;
+use std::io;
+use std::path::Path;
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
 // Create cryptography engine
 const ENGINE: engine::GeneralPurpose =
@@ -27,8 +35,60 @@ pub fn generate_public_key(key: RsaPrivateKey) -> RsaPublicKey {
 	RsaPublicKey::from(&key)
 }
 
+// Load the host's identity key, generating and storing one on first use
+pub fn load_or_generate_identity(path: &Path) -> io::Result<RsaPrivateKey> {
+	if path.exists() {
+		let pem = fs::read_to_string(path)?;
+		return RsaPrivateKey::from_pkcs1_pem(&pem).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+	}
+
+	let key = generate_private_key();
+	let pem = key
+		.to_pkcs1_pem(LineEnding::LF)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+	fs::write(path, pem.as_bytes())?;
+
+	// The identity key must only be readable by its owner
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::PermissionsExt;
+		fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+	}
+
+	Ok(key)
+}
+
+// Sign an announcement with the host's identity key
+pub fn sign(identity: &RsaPrivateKey, data: &[u8]) -> Vec<u8> {
+	SigningKey::<Sha256>::new(identity.clone()).sign(data).to_vec()
+}
+
+// Check an announcement's signature against the identity it claims to come from
+pub fn verify_signature(public_key: &str, data: &[u8], signature: &[u8]) -> bool {
+	let public_key = match public_key_from_string(public_key.to_string()) {
+		Some(key) => key,
+		None => return false,
+	};
+	let signature = match Signature::try_from(signature) {
+		Ok(signature) => signature,
+		Err(_) => return false,
+	};
+
+	VerifyingKey::<Sha256>::new(public_key).verify(data, &signature).is_ok()
+}
+
+// Short, human comparable digest of a shared public key
+pub fn identity_fingerprint(public_key: &str) -> String {
+	let hash = blake3::hash(public_key.as_bytes()).to_hex();
+	hash[..32]
+		.as_bytes()
+		.chunks(4)
+		.map(|group| std::str::from_utf8(group).unwrap())
+		.join(":")
+}
+
 // Turn the public_key to a string for sharing
-pub fn public_key_to_string(ref key: RsaPublicKey) -> String {
+pub fn public_key_to_string(key: &RsaPublicKey) -> String {
 	let data: Vec<u8> = key.to_pkcs1_der().unwrap().into_vec();
 	ENGINE.encode(data)
 }
@@ -45,95 +105,210 @@ pub fn public_key_from_string(key: String) -> Option<RsaPublicKey> {
 	Some(key)
 }
 
+// Generate a random key derivation salt for a share, base64 encoded for sharing
 pub fn generate_salt() -> String {
-	let mut rand = [0u8; 511];
-	getrandom::fill(&mut rand).expect("Unable to initialize passphrase backing array");
-	ENGINE.encode(rand)
+	let mut rand = [0u8; 16];
+	getrandom::fill(&mut rand).expect("Unable to initialize salt backing array");
+	SaltString::encode_b64(&rand)
+		.expect("Unable to encode salt")
+		.as_str()
+		.to_string()
 }
 
 // Function to turn the password into a hash, necessary for Aes256Gcm encryption
-pub fn derive_key(passphrase: &str) -> [u8; 32] {
-	// Argon2 requires a salt, but in our case we can only provide a fixed salt
-	// Even with a fixed salt argon is better than other hashing alghorithms that don't take a salt
-	let salt = SaltString::from_b64("OWQzczU4ZzEwZGQ3NXM1YTVmbzFqazI").unwrap(); // random, base64 encoded, pre generated string
+pub fn derive_key(
+	passphrase: &str,
+	salt: &str,
+	params: &KdfParams,
+) -> Result<[u8; 32], argon2::password_hash::Error> {
+	let salt = SaltString::from_b64(salt)?;
 	let argon2 = Argon2::new(
 		argon2::Algorithm::Argon2id,
 		argon2::Version::V0x13,
-		Params::new(65536, 4, 1, Some(32)).unwrap(),
+		Params::new(
+			params.memory_kib,
+			params.iterations,
+			params.parallelism,
+			Some(32),
+		)?,
 	);
 
-	let password_hash = argon2.hash_password(passphrase.as_bytes(), &salt).unwrap();
-	let key = password_hash.hash.unwrap();
+	let password_hash = argon2.hash_password(passphrase.as_bytes(), &salt)?;
+	let key = password_hash.hash.ok_or(argon2::password_hash::Error::OutputSize {
+		provided: std::cmp::Ordering::Less,
+		expected: 32,
+	})?;
 
-	key.as_bytes().try_into().expect("Invalid key length")
+	Ok(key.as_bytes().try_into().expect("Invalid key length"))
 }
 
-// Functions to encrypt and decrypt data using a key, (for file sharing)
+// Logins run SPAKE2 over the share key, so the passphrase never leaves the client
+// and both sides end up with a fresh session key
+
+const CLIENT_IDENTITY: &[u8] = b"redit client";
+const SERVER_IDENTITY: &[u8] = b"redit server";
+const CLIENT_CONFIRMATION: &[u8] = b"redit client confirmation";
+const SERVER_CONFIRMATION: &[u8] = b"redit server confirmation";
+const TRAFFIC_KEY_CONTEXT: &str = "redit session traffic key";
+
+pub type LoginState = Spake2<Ed25519Group>;
+
+// Public shares have no passphrase, their logins run over this well known key
+// and are authenticated by the host's signature alone
+pub const PUBLIC_SHARE_KEY: [u8; 32] = [0u8; 32];
+
+pub fn start_client_login(share_key: &[u8; 32]) -> (LoginState, Vec<u8>) {
+	Spake2::<Ed25519Group>::start_a(
+		&Password::new(share_key),
+		&Identity::new(CLIENT_IDENTITY),
+		&Identity::new(SERVER_IDENTITY),
+	)
+}
+
+pub fn start_server_login(share_key: &[u8; 32]) -> (LoginState, Vec<u8>) {
+	Spake2::<Ed25519Group>::start_b(
+		&Password::new(share_key),
+		&Identity::new(CLIENT_IDENTITY),
+		&Identity::new(SERVER_IDENTITY),
+	)
+}
+
+// Returns the session key, or None if the peer's message is malformed
+pub fn finish_login(state: LoginState, message: &[u8]) -> Option<[u8; 32]> {
+	let key = state.finish(message).ok()?;
+	key.try_into().ok()
+}
+
+// Every login also exchanges ephemeral X25519 keys, which are forgotten after the login
+pub fn generate_ephemeral_key() -> (EphemeralSecret, [u8; 32]) {
+	let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+	let public = PublicKey::from(&secret);
+	(secret, public.to_bytes())
+}
+
+// Mix the SPAKE2 key with the Diffie-Hellman secret, so recorded sessions stay
+// confidential even if the passphrase leaks later. Returns None for a peer key
+// that does not contribute to the shared secret.
+pub fn derive_traffic_key(
+	login_key: &[u8; 32],
+	secret: EphemeralSecret,
+	client_public: &[u8; 32],
+	server_public: &[u8; 32],
+	peer_public: &[u8; 32],
+) -> Option<[u8; 32]> {
+	let shared = secret.diffie_hellman(&PublicKey::from(*peer_public));
+	if !shared.was_contributory() {
+		return None;
+	}
+
+	let mut material = Vec::with_capacity(32 * 4);
+	material.extend_from_slice(login_key);
+	material.extend_from_slice(shared.as_bytes());
+	material.extend_from_slice(client_public);
+	material.extend_from_slice(server_public);
+	Some(blake3::derive_key(TRAFFIC_KEY_CONTEXT, &material))
+}
+
+// Proofs that a side derived the same session key, without revealing it
+pub fn client_confirmation(session_key: &[u8; 32]) -> [u8; 32] {
+	blake3::keyed_hash(session_key, CLIENT_CONFIRMATION).into()
+}
+
+pub fn server_confirmation(session_key: &[u8; 32]) -> [u8; 32] {
+	blake3::keyed_hash(session_key, SERVER_CONFIRMATION).into()
+}
+
+// Compare confirmations in constant time
+pub fn confirmation_matches(expected: [u8; 32], received: [u8; 32]) -> bool {
+	blake3::Hash::from(expected) == blake3::Hash::from(received)
+}
 
-pub fn encrypt_with_passphrase(data: &[u8], key: &[u8; 32]) -> Vec<u8> {
-	let cipher = Aes256Gcm::new(key.into());
-	let nonce = Nonce::from_slice(&[0u8; 12]);
+// Random value generated for every share, the payload nonces are derived from it
+pub fn generate_nonce_seed() -> [u8; 8] {
+	let mut seed = [0u8; 8];
+	getrandom::fill(&mut seed).expect("Unable to generate nonce seed");
+	seed
+}
 
-	cipher.encrypt(nonce, data).expect("encryption failure!")
+// The nonce of a payload is the share's nonce seed followed by the payload index,
+// so no nonce is ever reused for two different payloads under the same key
+fn payload_nonce(nonce_seed: &[u8; 8], index: u32) -> [u8; 12] {
+	let mut nonce = [0u8; 12];
+	nonce[..8].copy_from_slice(nonce_seed);
+	nonce[8..].copy_from_slice(&index.to_be_bytes());
+	nonce
 }
 
-pub fn decrypt_with_passphrase(encrypted_data: &[u8], key: &[u8; 32]) -> Vec<u8> {
-	let cipher = Aes256Gcm::new(key.into());
-	let nonce = Nonce::from_slice(&[0u8; 12]);
+// The index and payload count are authenticated, so payloads cannot be swapped, reordered or truncated
+fn payload_associated_data(index: u32, payload_count: u32) -> [u8; 8] {
+	let mut aad = [0u8; 8];
+	aad[..4].copy_from_slice(&index.to_be_bytes());
+	aad[4..].copy_from_slice(&payload_count.to_be_bytes());
+	aad
+}
 
-	let decrypted = cipher
-		.decrypt(nonce, encrypted_data)
-		.expect("decryption failure!");
+// Functions to encrypt and decrypt data using a key, (for file sharing)
 
-	decrypted
+// The payload cipher of a session, its key schedule is expanded once and reused for every payload
+#[derive(Clone)]
+pub struct PayloadCipher {
+	cipher: Aes256Gcm,
+	nonce_seed: [u8; 8],
 }
 
+impl PayloadCipher {
+	pub fn new(key: &[u8; 32], nonce_seed: [u8; 8]) -> Self {
+		PayloadCipher {
+			cipher: Aes256Gcm::new(key.into()),
+			nonce_seed,
+		}
+	}
+
+	pub fn encrypt(&self, data: &[u8], index: u32, payload_count: u32) -> Vec<u8> {
+		let nonce = payload_nonce(&self.nonce_seed, index);
+		let aad = payload_associated_data(index, payload_count);
+
+		self.cipher
+			.encrypt(Nonce::from_slice(&nonce), AeadPayload { msg: data, aad: &aad })
+			.expect("encryption failure!")
+	}
+
+	pub fn decrypt(&self, encrypted_data: &[u8], index: u32, payload_count: u32) -> Result<Vec<u8>, aes_gcm::Error> {
+		let nonce = payload_nonce(&self.nonce_seed, index);
+		let aad = payload_associated_data(index, payload_count);
+
+		self.cipher.decrypt(
+			Nonce::from_slice(&nonce),
+			AeadPayload {
+				msg: encrypted_data,
+				aad: &aad,
+			},
+		)
+	}
+}
+
+#[cfg(test)]
 mod tests {
 	use super::*;
+	#[allow(unused_imports)]
+	use crate::passphrase::generate_passphrase;
+	use crate::test_utils::TempDir;
 
 	#[allow(dead_code)]
 	fn encrypt(data: Vec<u8>, passphrase: String) -> String {
 		let fernet = fernet::Fernet::new(&key_from_passphrase(passphrase)).unwrap();
-		return fernet.encrypt(&data);
+		fernet.encrypt(&data)
 	}
 
 	#[allow(dead_code)]
 	fn decrypt(data: &str, passphrase: String) -> Result<Vec<u8>, fernet::DecryptionError> {
 		let fernet = fernet::Fernet::new(&key_from_passphrase(passphrase)).unwrap();
-		return fernet.decrypt(&data);
-	}
-
-	#[allow(dead_code)]
-	fn generate_passphrase(len: u8) -> String {
-		let mut passphrase: String = "".to_string();
-		let mut rand = [0u8; 511];
-		getrandom::fill(&mut rand).expect("Unable to initialize passphrase backing array");
-		let seed: Vec<u16> = rand
-			.into_iter()
-			.tuples()
-			.map(|(m, n)| ((m as u16) * 256 + n as u16) & 1023)
-			.collect::<Vec<_>>();
-		for i in 0..len {
-			let s = seed[i as usize];
-			passphrase.push_str(WORDS[s as usize]);
-			if i < len - 1 {
-				passphrase.push_str("-");
-			}
-		}
-		return passphrase;
+		fernet.decrypt(data)
 	}
 
 	fn key_from_passphrase(passphrase: String) -> String {
 		let hashed = blake3::hash(passphrase.as_bytes());
-		let encoded = ENGINE.encode(&hashed.to_string()[..32]);
-		return encoded;
-	}
-
-	#[test]
-	fn passphrase_range() {
-		for i in 0..255 {
-			generate_passphrase(i);
-		}
+		ENGINE.encode(&hashed.to_string()[..32])
 	}
 
 	#[test]
@@ -147,13 +322,39 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn test_identity_is_persistent() {
+		let dir = TempDir::new("identity");
+		let path = dir.join("identity.pem");
+
+		let generated = load_or_generate_identity(&path).unwrap();
+		let loaded = load_or_generate_identity(&path).unwrap();
+
+		assert_eq!(generated, loaded, "The identity must survive a restart");
+	}
+
+	#[test]
+	fn test_signatures() {
+		let identity = generate_private_key();
+		let public_key = public_key_to_string(&generate_public_key(identity.clone()));
+		let signature = sign(&identity, b"announcement");
+
+		assert!(verify_signature(&public_key, b"announcement", &signature));
+		assert!(!verify_signature(&public_key, b"forged announcement", &signature));
+		assert!(!verify_signature(&public_key, b"announcement", &signature[1..]));
+
+		let impostor = public_key_to_string(&generate_public_key(generate_private_key()));
+		assert!(!verify_signature(&impostor, b"announcement", &signature));
+	}
+
 	#[test]
 	fn test_derive_key() {
 		let passphrase = "my_secure_passphrase";
 		//TODO: upadte
 		let expected_key: [u8; 32] = [253, 233, 239, 148, 133, 120, 140, 215, 64, 76, 154, 25, 230, 68, 113, 252, 222, 74, 244, 76, 219, 189, 251, 69, 223, 4, 177, 49, 109, 163, 49, 95];
 
-		let derived_key = derive_key(passphrase);
+		let derived_key =
+			derive_key(passphrase, "OWQzczU4ZzEwZGQ3NXM1YTVmbzFqazI", &KdfParams::default()).unwrap();
 
 		assert_eq!(
 			derived_key, expected_key,
@@ -161,16 +362,33 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_derive_key_salts() {
+		let passphrase = "my_secure_passphrase";
+		let params = KdfParams {
+			memory_kib: 8 * 1024,
+			iterations: 1,
+			parallelism: 1,
+		};
+
+		let first = derive_key(passphrase, &generate_salt(), &params).unwrap();
+		let second = derive_key(passphrase, &generate_salt(), &params).unwrap();
+		assert_ne!(first, second, "Shares with the same passphrase must not share a key");
+
+		assert!(derive_key(passphrase, "not base64!", &params).is_err());
+	}
+
 	#[test]
 	fn test_encrypt_decrypt_with_passphrase() {
 		let passphrase = "my_secure_passphrase";
 		let data = b"Hello, this is a test message!";
 
-		let key = derive_key(passphrase);
+		let key = derive_key(passphrase, &generate_salt(), &KdfParams::default()).unwrap();
+		let cipher = PayloadCipher::new(&key, generate_nonce_seed());
 
-		let encrypted_data = encrypt_with_passphrase(data, &key);
+		let encrypted_data = cipher.encrypt(data, 3, 8);
 
-		let decrypted_data = decrypt_with_passphrase(&encrypted_data, &key);
+		let decrypted_data = cipher.decrypt(&encrypted_data, 3, 8).unwrap();
 
 		assert_eq!(
 			data.to_vec(),
@@ -178,5 +396,86 @@ mod tests {
 			"Decrypted data should match the original data."
 		);
 	}
+
+	#[test]
+	fn test_login() {
+		let share_key = [3u8; 32];
+
+		let (client_state, client_message) = start_client_login(&share_key);
+		let (server_state, server_message) = start_server_login(&share_key);
+		let client_key = finish_login(client_state, &server_message).unwrap();
+		let server_key = finish_login(server_state, &client_message).unwrap();
+
+		assert_eq!(client_key, server_key);
+		assert!(confirmation_matches(server_confirmation(&client_key), server_confirmation(&server_key)));
+		assert!(!confirmation_matches(client_confirmation(&client_key), server_confirmation(&server_key)));
+	}
+
+	#[test]
+	fn test_login_wrong_password() {
+		let (client_state, client_message) = start_client_login(&[3u8; 32]);
+		let (server_state, server_message) = start_server_login(&[4u8; 32]);
+		let client_key = finish_login(client_state, &server_message).unwrap();
+		let server_key = finish_login(server_state, &client_message).unwrap();
+
+		assert_ne!(client_key, server_key);
+		assert!(!confirmation_matches(server_confirmation(&client_key), server_confirmation(&server_key)));
+	}
+
+	#[test]
+	fn test_traffic_key() {
+		let login_key = [5u8; 32];
+		let (client_secret, client_public) = generate_ephemeral_key();
+		let (server_secret, server_public) = generate_ephemeral_key();
+
+		let client_key =
+			derive_traffic_key(&login_key, client_secret, &client_public, &server_public, &server_public).unwrap();
+		let server_key =
+			derive_traffic_key(&login_key, server_secret, &client_public, &server_public, &client_public).unwrap();
+		assert_eq!(client_key, server_key);
+		assert_ne!(client_key, login_key, "The traffic key must not be the passphrase derived key");
+
+		// Another session with the same passphrase gets another key
+		let (client_secret, client_public) = generate_ephemeral_key();
+		let (_, server_public) = generate_ephemeral_key();
+		let other_key =
+			derive_traffic_key(&login_key, client_secret, &client_public, &server_public, &server_public).unwrap();
+		assert_ne!(client_key, other_key);
+	}
+
+	#[test]
+	fn test_traffic_key_rejects_low_order_points() {
+		let (secret, public) = generate_ephemeral_key();
+		assert!(derive_traffic_key(&[5u8; 32], secret, &public, &[0u8; 32], &[0u8; 32]).is_none());
+	}
+
+	#[test]
+	fn test_payload_nonces_are_unique() {
+		let key = [7u8; 32];
+		let cipher = PayloadCipher::new(&key, generate_nonce_seed());
+		let data = [0u8; 64];
+
+		let first = cipher.encrypt(&data, 0, 2);
+		let second = cipher.encrypt(&data, 1, 2);
+		assert_ne!(first, second, "Equal plaintexts at different indices must not encrypt alike");
+
+		let other_share = PayloadCipher::new(&key, generate_nonce_seed()).encrypt(&data, 0, 2);
+		assert_ne!(first, other_share, "Equal plaintexts in different shares must not encrypt alike");
+	}
+
+	#[test]
+	fn test_payloads_cannot_be_moved() {
+		let key = [7u8; 32];
+		let cipher = PayloadCipher::new(&key, generate_nonce_seed());
+		let encrypted_data = cipher.encrypt(b"payload", 4, 10);
+
+		// Swapped to another index
+		assert!(cipher.decrypt(&encrypted_data, 5, 10).is_err());
+		// Presented as part of a truncated file
+		assert!(cipher.decrypt(&encrypted_data, 4, 5).is_err());
+		// Replayed from another share
+		let other_share = PayloadCipher::new(&key, generate_nonce_seed());
+		assert!(other_share.decrypt(&encrypted_data, 4, 10).is_err());
+	}
 }
 
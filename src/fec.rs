@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::types::{FecParams, RepairSymbol};
+
+// Groups the host still waits to send all payloads of, the oldest is dropped beyond this
+const MAX_PENDING_GROUPS: usize = 8;
+
+// Memory the client spends on groups that may still need repairing
+const MAX_OPEN_BYTES: usize = 16 * 1024 * 1024;
+
+fn group_of(params: &FecParams, index: u32) -> u32 {
+	index / u32::from(params.group_size)
+}
+
+// The payload indices of a group, the last group of a file may be shorter
+fn group_range(params: &FecParams, group: u32, payload_count: u32) -> Range<u32> {
+	let start = group.saturating_mul(params.group_size.into()).min(payload_count);
+	let end = start.saturating_add(params.group_size.into()).min(payload_count);
+	start..end
+}
+
+// Payloads are coded as equally long shards, shorter ones are padded with zeros
+fn to_shard(data: &[u8], shard_size: usize) -> Vec<u8> {
+	let mut shard = data.to_vec();
+	shard.resize(shard_size, 0);
+	shard
+}
+
+struct PendingGroup {
+	group: u32,
+	shards: Vec<Option<Vec<u8>>>,
+}
+
+// Collects the encrypted payloads of a session as they are sent, and codes repair
+// symbols for every group once all of its payloads went out
+pub struct RepairEncoder {
+	params: FecParams,
+	shard_size: usize,
+	pending: VecDeque<PendingGroup>,
+}
+
+impl RepairEncoder {
+	pub fn new(params: FecParams, shard_size: usize) -> Self {
+		RepairEncoder {
+			params,
+			shard_size,
+			pending: VecDeque::new(),
+		}
+	}
+
+	// Note a sent payload, returns the repair symbols of its group if it was the last one missing
+	pub fn on_sent(&mut self, index: u32, payload_count: u32, data: &[u8]) -> Vec<RepairSymbol> {
+		let group = group_of(&self.params, index);
+		let range = group_range(&self.params, group, payload_count);
+		if !range.contains(&index) || data.len() > self.shard_size {
+			return Vec::new();
+		}
+
+		let position = match self.pending.iter().position(|pending| pending.group == group) {
+			Some(position) => position,
+			None => {
+				if self.pending.len() >= MAX_PENDING_GROUPS {
+					self.pending.pop_front();
+				}
+				self.pending.push_back(PendingGroup {
+					group,
+					shards: vec![None; range.len()],
+				});
+				self.pending.len() - 1
+			}
+		};
+
+		let pending = &mut self.pending[position];
+		pending.shards[(index - range.start) as usize] = Some(to_shard(data, self.shard_size));
+		if pending.shards.iter().any(Option::is_none) {
+			return Vec::new();
+		}
+
+		let Some(pending) = self.pending.remove(position) else {
+			return Vec::new();
+		};
+		let repair_symbols = usize::from(self.params.repair_symbols);
+		let mut shards: Vec<Vec<u8>> = pending.shards.into_iter().flatten().collect();
+		shards.resize(range.len() + repair_symbols, vec![0; self.shard_size]);
+
+		let coded = ReedSolomon::new(range.len(), repair_symbols).and_then(|coder| coder.encode(&mut shards));
+		if coded.is_err() {
+			return Vec::new();
+		}
+
+		shards
+			.split_off(range.len())
+			.into_iter()
+			.enumerate()
+			.map(|(symbol, data)| RepairSymbol {
+				group,
+				symbol: symbol as u8,
+				data,
+			})
+			.collect()
+	}
+}
+
+struct OpenGroup {
+	group: u32,
+	data: Vec<Option<Vec<u8>>>,
+	repair: Vec<Option<Vec<u8>>>,
+}
+
+// Keeps the encrypted payloads of recent groups, so lost ones can be rebuilt from the
+// repair symbols the host sends after each group instead of being requested again
+pub struct RepairDecoder {
+	params: FecParams,
+	shard_size: usize,
+	payload_count: u32,
+	last_size: usize,
+	max_open: usize,
+	open: VecDeque<OpenGroup>,
+	recovered: u64,
+}
+
+impl RepairDecoder {
+	// `last_size` is the length of the last, possibly shorter, encrypted payload
+	pub fn new(params: FecParams, shard_size: usize, payload_count: u32, last_size: usize) -> Self {
+		let group_bytes = shard_size.saturating_mul(usize::from(params.group_size) + usize::from(params.repair_symbols));
+		RepairDecoder {
+			params,
+			shard_size,
+			payload_count,
+			last_size,
+			max_open: (MAX_OPEN_BYTES / group_bytes.max(1)).max(2),
+			open: VecDeque::new(),
+			recovered: 0,
+		}
+	}
+
+	// Keep a payload that arrived, returns the payloads of its group it allowed to rebuild
+	pub fn on_payload(&mut self, index: u32, data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+		if index >= self.payload_count || data.len() != self.payload_len(index) {
+			return Vec::new();
+		}
+
+		let group = group_of(&self.params, index);
+		let start = group_range(&self.params, group, self.payload_count).start;
+		let position = match self.open.iter().position(|open| open.group == group) {
+			Some(position) => position,
+			None => self.open_group(group),
+		};
+
+		self.open[position].data[(index - start) as usize] = Some(to_shard(data, self.shard_size));
+		self.recover(position)
+	}
+
+	// Keep a repair symbol, returns the payloads of its group it allowed to rebuild
+	pub fn on_repair(&mut self, symbol: RepairSymbol) -> Vec<(u32, Vec<u8>)> {
+		if symbol.symbol >= self.params.repair_symbols || symbol.data.len() != self.shard_size {
+			return Vec::new();
+		}
+
+		// Groups without any payload that arrived are lost beyond repair, or already complete
+		let Some(position) = self.open.iter().position(|open| open.group == symbol.group) else {
+			return Vec::new();
+		};
+
+		self.open[position].repair[usize::from(symbol.symbol)] = Some(symbol.data);
+		self.recover(position)
+	}
+
+	// Payloads rebuilt from repair symbols so far
+	pub fn recovered(&self) -> u64 {
+		self.recovered
+	}
+
+	fn payload_len(&self, index: u32) -> usize {
+		if index + 1 == self.payload_count {
+			self.last_size
+		} else {
+			self.shard_size
+		}
+	}
+
+	fn open_group(&mut self, group: u32) -> usize {
+		if self.open.len() >= self.max_open {
+			self.open.pop_front();
+		}
+
+		let range = group_range(&self.params, group, self.payload_count);
+		self.open.push_back(OpenGroup {
+			group,
+			data: vec![None; range.len()],
+			repair: vec![None; self.params.repair_symbols.into()],
+		});
+		self.open.len() - 1
+	}
+
+	// Rebuild the missing payloads of a group once enough of its shards are there
+	fn recover(&mut self, position: usize) -> Vec<(u32, Vec<u8>)> {
+		let open = &self.open[position];
+		let missing = open.data.iter().filter(|shard| shard.is_none()).count();
+		let repairs = open.repair.iter().filter(|shard| shard.is_some()).count();
+		if missing > 0 && repairs < missing {
+			return Vec::new();
+		}
+
+		// The group is either complete or about to be, either way it is done with
+		let Some(open) = self.open.remove(position) else {
+			return Vec::new();
+		};
+		if missing == 0 {
+			return Vec::new();
+		}
+
+		let data_shards = open.data.len();
+		let start = group_range(&self.params, open.group, self.payload_count).start;
+		let lost: Vec<usize> = (0..data_shards).filter(|&shard| open.data[shard].is_none()).collect();
+
+		let mut shards: Vec<Option<Vec<u8>>> = open.data.into_iter().chain(open.repair).collect();
+		let rebuilt = ReedSolomon::new(data_shards, self.params.repair_symbols.into())
+			.and_then(|coder| coder.reconstruct_data(&mut shards));
+		if rebuilt.is_err() {
+			return Vec::new();
+		}
+
+		let mut recovered = Vec::with_capacity(lost.len());
+		for shard in lost {
+			let index = start + shard as u32;
+			if let Some(mut data) = shards[shard].take() {
+				data.truncate(self.payload_len(index));
+				recovered.push((index, data));
+			}
+		}
+		self.recovered += recovered.len() as u64;
+		recovered
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::{Payload, ReditPacket};
+	use rand::rngs::StdRng;
+	use rand::{Rng, RngCore, SeedableRng};
+
+	const SHARD_SIZE: usize = 64;
+
+	fn payloads(count: u32, last_size: usize, rng: &mut StdRng) -> Vec<Vec<u8>> {
+		(0..count)
+			.map(|index| {
+				let size = if index + 1 == count { last_size } else { SHARD_SIZE };
+				let mut data = vec![0; size];
+				rng.fill_bytes(&mut data);
+				data
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_recovery_under_simulated_loss() {
+		let params = FecParams {
+			group_size: 8,
+			repair_symbols: 3,
+		};
+		let mut rng = StdRng::seed_from_u64(17);
+		// 50 full groups and a short last one, ending in a short payload
+		let count = 405;
+		let last_size = 21;
+		let sent = payloads(count, last_size, &mut rng);
+
+		let mut encoder = RepairEncoder::new(params, SHARD_SIZE);
+		let mut decoder = RepairDecoder::new(params, SHARD_SIZE, count, last_size);
+		let mut received = vec![None; count as usize];
+		let mut lost_in_total = 0;
+		let mut recoverable = 0;
+
+		for group in 0..count.div_ceil(8) {
+			let range = group_range(&params, group, count);
+			let mut arrived = 0;
+			let mut lost = 0;
+
+			let mut repairs = Vec::new();
+			for index in range.clone() {
+				repairs.extend(encoder.on_sent(index, count, &sent[index as usize]));
+
+				// One in five payloads is lost
+				if rng.gen_bool(0.2) {
+					lost += 1;
+					continue;
+				}
+				arrived += 1;
+				received[index as usize] = Some(sent[index as usize].clone());
+				for (index, data) in decoder.on_payload(index, &sent[index as usize]) {
+					received[index as usize] = Some(data);
+				}
+			}
+
+			assert_eq!(repairs.len(), 3, "Every group sent in full gets its repair symbols");
+			for symbol in repairs {
+				if rng.gen_bool(0.2) {
+					continue;
+				}
+				arrived += 1;
+				for (index, data) in decoder.on_repair(symbol) {
+					received[index as usize] = Some(data);
+				}
+			}
+
+			lost_in_total += lost;
+			if arrived >= range.len() {
+				recoverable += lost;
+			}
+		}
+
+		assert!(recoverable > 0, "The simulated loss should leave something to repair");
+		assert_eq!(decoder.recovered(), recoverable);
+		for (index, data) in received.iter().enumerate() {
+			if let Some(data) = data {
+				assert_eq!(data, &sent[index], "Payload {} was rebuilt wrong", index);
+			}
+		}
+		let missing = received.iter().filter(|data| data.is_none()).count() as u64;
+		assert_eq!(missing, lost_in_total - recoverable, "Only groups that lost too much stay incomplete");
+	}
+
+	#[test]
+	fn test_partially_sent_groups_get_no_repair_symbols() {
+		let params = FecParams {
+			group_size: 4,
+			repair_symbols: 2,
+		};
+		let mut encoder = RepairEncoder::new(params, SHARD_SIZE);
+
+		// A retransmission of a single payload does not complete its group
+		assert!(encoder.on_sent(5, 12, &[1; SHARD_SIZE]).is_empty());
+		assert!(encoder.on_sent(1, 12, &[1; SHARD_SIZE]).is_empty());
+		assert!(encoder.on_sent(2, 12, &[1; SHARD_SIZE]).is_empty());
+		assert!(encoder.on_sent(3, 12, &[1; SHARD_SIZE]).is_empty());
+		assert_eq!(encoder.on_sent(0, 12, &[1; SHARD_SIZE]).len(), 2);
+		assert!(encoder.on_sent(12, 12, &[1; SHARD_SIZE]).is_empty(), "Out of range");
+	}
+
+	#[test]
+	fn test_corrupt_symbols_are_ignored() {
+		let params = FecParams {
+			group_size: 4,
+			repair_symbols: 1,
+		};
+		let mut decoder = RepairDecoder::new(params, SHARD_SIZE, 8, SHARD_SIZE);
+		assert!(decoder.on_payload(0, &[1; SHARD_SIZE]).is_empty());
+
+		let short = RepairSymbol {
+			group: 0,
+			symbol: 0,
+			data: vec![0; SHARD_SIZE - 1],
+		};
+		let unknown = RepairSymbol {
+			group: 0,
+			symbol: 1,
+			data: vec![0; SHARD_SIZE],
+		};
+		assert!(decoder.on_repair(short).is_empty());
+		assert!(decoder.on_repair(unknown).is_empty());
+		assert_eq!(decoder.recovered(), 0);
+	}
+
+	#[test]
+	fn test_repair_symbols_fit_datagrams() {
+		// Repair symbols go out on the same path as payloads and must not exceed them
+		let payload = ReditPacket::Payload(Payload {
+			success: true,
+			index: u32::MAX,
+			payload_count: u32::MAX,
+			data: vec![0; SHARD_SIZE],
+		});
+		let repair = ReditPacket::RepairSymbol(RepairSymbol {
+			group: u32::MAX,
+			symbol: u8::MAX,
+			data: vec![0; SHARD_SIZE],
+		});
+		assert!(repair.encode().len() <= payload.encode().len());
+	}
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+// Trust on first use: the first identity a host name presents is pinned, every
+// later connection must present the same one. Entries are stored one per line
+// as tab separated name, last known address and public key. Names come off the network,
+// so ones that could break a line apart are never pinned.
+
+struct KnownHost {
+	name: String,
+	address: IpAddr,
+	public_key: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Trust {
+	// The host presented the identity pinned for its name
+	Known,
+	// The host was never seen before
+	New,
+	// The host presented another identity than the one pinned for its name
+	Changed { pinned_key: String, pinned_address: IpAddr },
+}
+
+pub struct KnownHosts {
+	path: PathBuf,
+	hosts: Vec<KnownHost>,
+}
+
+impl KnownHosts {
+	pub fn load(path: &Path) -> io::Result<Self> {
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+
+		let mut hosts = Vec::new();
+		for line in contents.lines() {
+			let mut fields = line.split('\t');
+			let (name, address, public_key) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+				(Some(name), Some(address), Some(public_key), None) => (name, address, public_key),
+				_ => continue,
+			};
+			let address = match address.parse() {
+				Ok(address) => address,
+				Err(_) => continue,
+			};
+
+			hosts.push(KnownHost {
+				name: name.to_string(),
+				address,
+				public_key: public_key.to_string(),
+			});
+		}
+
+		Ok(KnownHosts {
+			path: path.to_path_buf(),
+			hosts,
+		})
+	}
+
+	// A name pinned more than once, in a file edited by hand, is only known if every pin agrees
+	pub fn check(&self, name: &str, public_key: &str) -> Trust {
+		let mut pins = self.hosts.iter().filter(|host| host.name == name).peekable();
+		if pins.peek().is_none() {
+			return Trust::New;
+		}
+		match pins.find(|host| host.public_key != public_key) {
+			Some(host) => Trust::Changed {
+				pinned_key: host.public_key.clone(),
+				pinned_address: host.address,
+			},
+			None => Trust::Known,
+		}
+	}
+
+	// Pin the identity of a host, replacing an earlier pin of the same name
+	pub fn pin(&mut self, name: &str, address: IpAddr, public_key: &str) -> io::Result<()> {
+		if !is_valid_field(name) || !is_valid_field(public_key) {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"host names and keys must not contain control characters",
+			));
+		}
+		self.hosts.retain(|host| host.name != name);
+		self.hosts.push(KnownHost {
+			name: name.to_string(),
+			address,
+			public_key: public_key.to_string(),
+		});
+		self.save()
+	}
+
+	fn save(&self) -> io::Result<()> {
+		let contents: String = self
+			.hosts
+			.iter()
+			.map(|host| format!("{}\t{}\t{}\n", host.name, host.address, host.public_key))
+			.collect();
+		fs::write(&self.path, contents)
+	}
+}
+
+// Whether a name or key can be stored without breaking the file apart
+pub fn is_valid_field(field: &str) -> bool {
+	!field.is_empty() && !field.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+
+	#[test]
+	fn test_trust_on_first_use() {
+		let dir = TempDir::new("known-hosts");
+		let path = dir.join("known_hosts");
+		let address: IpAddr = "192.168.1.20".parse().unwrap();
+
+		let mut known_hosts = KnownHosts::load(&path).unwrap();
+		assert_eq!(known_hosts.check("builds", "key-a"), Trust::New);
+		known_hosts.pin("builds", address, "key-a").unwrap();
+
+		// Pins survive a restart
+		let known_hosts = KnownHosts::load(&path).unwrap();
+		assert_eq!(known_hosts.check("builds", "key-a"), Trust::Known);
+		assert_eq!(known_hosts.check("datasets", "key-b"), Trust::New);
+		assert_eq!(
+			known_hosts.check("builds", "key-b"),
+			Trust::Changed {
+				pinned_key: "key-a".to_string(),
+				pinned_address: address,
+			}
+		);
+	}
+
+	#[test]
+	fn test_names_cannot_add_pins() {
+		let dir = TempDir::new("known-hosts");
+		let path = dir.join("known_hosts");
+		let address: IpAddr = "192.168.1.20".parse().unwrap();
+
+		let mut known_hosts = KnownHosts::load(&path).unwrap();
+		for name in ["x\t192.168.1.66\tevil-key\nvictim", "victim\n", "tab\tbed", ""] {
+			assert_eq!(known_hosts.pin(name, address, "key-a").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+		}
+		assert!(known_hosts.pin("victim", address, "key\nsecond").is_err());
+		assert!(!path.exists(), "Nothing was written");
+		assert_eq!(KnownHosts::load(&path).unwrap().check("victim", "evil-key"), Trust::New);
+
+		// A line smuggled in by hand still does not let another key pass for the pinned one
+		fs::write(&path, "victim\t192.168.1.20\tkey-a\nvictim\t192.168.1.66\tevil-key\textra\nvictim\t192.168.1.66\tevil-key\n").unwrap();
+		let known_hosts = KnownHosts::load(&path).unwrap();
+		assert!(matches!(known_hosts.check("victim", "evil-key"), Trust::Changed { .. }));
+		assert!(matches!(known_hosts.check("victim", "key-a"), Trust::Changed { .. }));
+	}
+}
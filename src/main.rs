@@ -1,13 +1,36 @@
+mod assembler;
+mod catalog;
 mod client;
+mod congestion;
+mod content;
+mod control;
 mod encryption;
+mod fec;
+mod known_hosts;
 mod logger;
+mod merkle;
+mod mtu;
+mod pacer;
+mod passphrase;
+mod policy;
+mod retransmit;
 mod scan;
 mod server;
+mod session;
+mod swarm;
+#[cfg(test)]
+mod test_utils;
 mod types;
 mod utils;
 mod words;
+mod workers;
 use argh::FromArgs;
+use control::ControlRequest;
 use logger::{log_error, log_info};
+use policy::{parse_duration, parse_window, Availability, ServeWindow};
+use std::time::Duration;
+use retransmit::RetryPolicy;
+use types::{FecParams, KdfParams};
 
 /// Redit file sharing
 #[derive(FromArgs)]
@@ -28,12 +51,30 @@ struct Cli {
 enum Commands {
 	Scan(ScanCommand),
 	Host(HostCommand),
+	Share(ShareCommand),
+	Status(StatusCommand),
 }
 
 /// Scan network for Redit distributors
 #[derive(FromArgs)]
 #[argh(subcommand, name = "scan")]
-struct ScanCommand {}
+struct ScanCommand {
+	/// give up downloading after requesting a payload this many times
+	#[argh(option, default = "RetryPolicy::default().max_attempts")]
+	retries: u32,
+
+	/// repair symbols to ask for per group of payloads, 0 disables forward error correction
+	#[argh(option, default = "FecParams::default().repair_symbols")]
+	fec: u8,
+
+	/// payloads per group that repair symbols are coded over
+	#[argh(option, default = "FecParams::default().group_size")]
+	fec_group: u8,
+
+	/// keep sharing the download under this name once it completed
+	#[argh(option)]
+	seed: Option<String>,
+}
 
 /// Host file on local network via Redit
 #[derive(FromArgs)]
@@ -45,6 +86,10 @@ struct HostCommand {
 	#[argh(positional)]
 	name: String,
 
+	/// share another file or directory from the same host, may be repeated
+	#[argh(option)]
+	add: Vec<std::path::PathBuf>,
+
 	/// make the content available to everyone
 	#[argh(switch)]
 	no_passphrase: bool,
@@ -52,8 +97,109 @@ struct HostCommand {
 	/// use a custom passphrase forcibly
 	#[argh(option)]
 	passphrase: Option<String>,
+
+	/// number of words in a generated passphrase
+	#[argh(option, default = "passphrase::DEFAULT_WORD_COUNT")]
+	words: u8,
+
+	/// argon2 memory cost of the share key in KiB
+	#[argh(option, default = "KdfParams::default().memory_kib")]
+	kdf_memory: u32,
+
+	/// argon2 iteration count of the share key
+	#[argh(option, default = "KdfParams::default().iterations")]
+	kdf_iterations: u32,
+
+	/// argon2 parallelism of the share key
+	#[argh(option, default = "KdfParams::default().parallelism")]
+	kdf_parallelism: u32,
+
+	/// keep hosting and take commands from `redit share` and `redit status`
+	#[argh(switch)]
+	daemon: bool,
+
+	/// stop serving the shares after this long, such as 30m, 12h or 7d
+	#[argh(option, from_str_fn(parse_duration))]
+	expires: Option<Duration>,
+
+	/// stop taking logins after this many complete downloads of a share
+	#[argh(option)]
+	max_downloads: Option<u32>,
+
+	/// only serve during this time of day in UTC, such as 09:00-17:00
+	#[argh(option, from_str_fn(parse_window))]
+	window: Option<ServeWindow>,
+}
+
+/// Change what a running host daemon shares
+#[derive(FromArgs)]
+#[argh(subcommand, name = "share")]
+struct ShareCommand {
+	#[argh(subcommand)]
+	action: ShareAction,
+}
+
+/// Share subcommands
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum ShareAction {
+	Add(ShareAddCommand),
+	Remove(ShareRemoveCommand),
+	List(ShareListCommand),
+	Rotate(ShareRotateCommand),
+}
+
+/// Share another file or directory
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add")]
+struct ShareAddCommand {
+	#[argh(positional)]
+	path: std::path::PathBuf,
+
+	/// stop serving the share after this long, such as 30m, 12h or 7d
+	#[argh(option, from_str_fn(parse_duration))]
+	expires: Option<Duration>,
+
+	/// stop taking logins after this many complete downloads
+	#[argh(option)]
+	max_downloads: Option<u32>,
+
+	/// only serve during this time of day in UTC, such as 09:00-17:00
+	#[argh(option, from_str_fn(parse_window))]
+	window: Option<ServeWindow>,
+}
+
+/// Stop sharing a share and log out its clients
+#[derive(FromArgs)]
+#[argh(subcommand, name = "remove")]
+struct ShareRemoveCommand {
+	#[argh(positional)]
+	share_id: u32,
+}
+
+/// List the shares
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+struct ShareListCommand {}
+
+/// Give the shares a new passphrase, clients that logged in keep their sessions
+#[derive(FromArgs)]
+#[argh(subcommand, name = "rotate")]
+struct ShareRotateCommand {
+	/// use a custom passphrase forcibly
+	#[argh(option)]
+	passphrase: Option<String>,
+
+	/// number of words in a generated passphrase
+	#[argh(option, default = "passphrase::DEFAULT_WORD_COUNT")]
+	words: u8,
 }
 
+/// Show what a running host daemon shares and who downloads it
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+struct StatusCommand {}
+
 fn main() {
 	log_info("Starting Redit");
 
@@ -67,13 +213,73 @@ fn main() {
 
 	let command = cli.command.unwrap();
 	match command {
-		Commands::Scan(_command) => client::scan(),
-		Commands::Host(command) => server::host(
-			command.no_passphrase,
-			command.path,
-			command.name,
-			command.passphrase,
-		),
+		Commands::Scan(command) => {
+			if command.retries == 0 {
+				log_error("At least one request per payload is needed");
+				return;
+			}
+
+			let fec = FecParams {
+				group_size: command.fec_group,
+				repair_symbols: command.fec,
+			};
+			if command.fec > 0 && !fec.is_acceptable() {
+				log_error("The forward error correction parameters are out of the range hosts accept");
+				return;
+			}
+
+			client::scan(
+				RetryPolicy {
+					max_attempts: command.retries,
+					..RetryPolicy::default()
+				},
+				(command.fec > 0).then_some(fec),
+				command.seed,
+			)
+		}
+		Commands::Host(command) => {
+			let kdf_params = KdfParams {
+				memory_kib: command.kdf_memory,
+				iterations: command.kdf_iterations,
+				parallelism: command.kdf_parallelism,
+			};
+			if !kdf_params.is_acceptable() {
+				log_error("The key derivation parameters are out of the range clients accept");
+				return;
+			}
+
+			// Every share of the command line is served on the same terms
+			let availability = Availability::new(command.expires, command.max_downloads, command.window);
+			server::host(
+				command.no_passphrase,
+				std::iter::once(command.path)
+					.chain(command.add)
+					.map(|path| (path, availability))
+					.collect(),
+				command.name,
+				command.passphrase,
+				command.words,
+				kdf_params,
+				command.daemon,
+			)
+		}
+		Commands::Share(command) => match command.action {
+			// The daemon runs elsewhere, paths are relative to where the command is given
+			ShareAction::Add(add) => control::run(ControlRequest::AddShare {
+				availability: Availability::new(add.expires, add.max_downloads, add.window),
+				path: std::path::absolute(&add.path).unwrap_or(add.path),
+			}),
+			ShareAction::Remove(remove) => control::run(ControlRequest::RemoveShare {
+				share_id: remove.share_id,
+			}),
+			ShareAction::List(_) => control::run(ControlRequest::ListShares),
+			ShareAction::Rotate(rotate) => {
+				if let Some(passphrase) = server::resolve_passphrase(rotate.passphrase, rotate.words) {
+					control::run(ControlRequest::RotatePassphrase { passphrase })
+				}
+			}
+		},
+		Commands::Status(_) => control::run(ControlRequest::Status),
 	}
 }
 
@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+// Files are hashed in blocks of this size, the leaves of their Merkle tree. Payloads are
+// checked a block at a time, so they can have any size and the tree of a large file stays small.
+pub const BLOCK_SIZE: u32 = 64 * 1024;
+
+// Leaves and parents are hashed in different contexts, so neither can pass for the other
+const LEAF_CONTEXT: &str = "redit 2025-01 merkle leaf";
+const PARENT_CONTEXT: &str = "redit 2025-01 merkle parent";
+
+pub fn leaf_hash(block: &[u8]) -> [u8; 32] {
+	let mut hasher = blake3::Hasher::new_derive_key(LEAF_CONTEXT);
+	hasher.update(block);
+	*hasher.finalize().as_bytes()
+}
+
+pub fn parent_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+	let mut hasher = blake3::Hasher::new_derive_key(PARENT_CONTEXT);
+	hasher.update(left);
+	hasher.update(right);
+	*hasher.finalize().as_bytes()
+}
+
+// The level above `nodes`. Neighbours are paired up, a lone last node is carried up as it is.
+fn parent_level(nodes: &[[u8; 32]]) -> Vec<[u8; 32]> {
+	nodes
+		.chunks(2)
+		.map(|pair| match pair {
+			[left, right] => parent_hash(left, right),
+			_ => pair[0],
+		})
+		.collect()
+}
+
+// Root of the tree that has `nodes` as one of its levels
+pub fn root_of(nodes: &[[u8; 32]]) -> [u8; 32] {
+	if nodes.is_empty() {
+		return leaf_hash(&[]);
+	}
+
+	let mut level = nodes.to_vec();
+	while level.len() > 1 {
+		level = parent_level(&level);
+	}
+	level[0]
+}
+
+// Read until `buf` is full or the reader ends, returns how much was read
+fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		match reader.read(&mut buf[filled..]) {
+			Ok(0) => break,
+			Ok(read) => filled += read,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(filled)
+}
+
+// Merkle tree over the blocks of a file. Only the leaves are kept, they are all clients
+// ask for, so a share costs the host 32 bytes per block.
+pub struct MerkleTree {
+	leaves: Vec<[u8; 32]>,
+	root: [u8; 32],
+}
+
+impl MerkleTree {
+	pub fn from_file(path: &Path) -> io::Result<Self> {
+		let mut reader = BufReader::new(File::open(path)?);
+		let mut buf = vec![0; BLOCK_SIZE as usize];
+		let mut leaves = Vec::new();
+		loop {
+			let read = read_block(&mut reader, &mut buf)?;
+			if read == 0 {
+				break;
+			}
+			leaves.push(leaf_hash(&buf[..read]));
+		}
+		if leaves.is_empty() {
+			leaves.push(leaf_hash(&[]));
+		}
+
+		let root = root_of(&leaves);
+		Ok(MerkleTree { leaves, root })
+	}
+
+	pub fn root(&self) -> [u8; 32] {
+		self.root
+	}
+
+	// Hashes of the blocks, in order
+	pub fn leaves(&self) -> &[[u8; 32]] {
+		&self.leaves
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+
+	#[test]
+	fn test_tree_over_blocks() {
+		let data: Vec<u8> = (0..4 * BLOCK_SIZE + 300).map(|i| (i * 13) as u8).collect();
+		let dir = TempDir::new("merkle");
+		let tree = MerkleTree::from_file(&dir.file("data", &data)).unwrap();
+
+		let leaves: Vec<[u8; 32]> = data.chunks(BLOCK_SIZE as usize).map(leaf_hash).collect();
+		assert_eq!(tree.leaves(), leaves.as_slice());
+		// The short last block is carried up until it has a neighbour
+		let left = parent_hash(&parent_hash(&leaves[0], &leaves[1]), &parent_hash(&leaves[2], &leaves[3]));
+		assert_eq!(tree.root(), parent_hash(&left, &leaves[4]));
+	}
+
+	#[test]
+	fn test_leaves_cannot_pass_for_parents() {
+		let left = leaf_hash(b"left");
+		let right = leaf_hash(b"right");
+		let mut joined = left.to_vec();
+		joined.extend_from_slice(&right);
+		assert_ne!(parent_hash(&left, &right), leaf_hash(&joined));
+	}
+}
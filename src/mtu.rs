@@ -0,0 +1,141 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::logger::log_info;
+use crate::types::{payload_overhead, MtuProbe, ReditPacket, PAYLOAD_SIZE};
+
+// Every IPv4 and IPv6 path carries datagrams of this size without fragmenting them
+pub const MIN_DATAGRAM_SIZE: usize = 1200;
+
+// Datagram sizes that fill common MTUs, jumbo frames and Ethernet, after the IP and UDP headers
+const PROBE_SIZES: [usize; 4] = [8972, 1472, 1452, 1232];
+const PROBE_ROUNDS: u32 = 3;
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+
+// Payloads are never smaller than this, whatever the path carries
+pub const MIN_PAYLOAD_SIZE: u32 = 1024;
+
+// Largest payload whose Payload packet fits a datagram of `datagram_size` bytes
+pub fn payload_size_for(datagram_size: usize) -> u32 {
+	let payload_size = datagram_size.saturating_sub(payload_overhead());
+	u32::try_from(payload_size)
+		.unwrap_or(u32::MAX)
+		.clamp(MIN_PAYLOAD_SIZE, PAYLOAD_SIZE)
+}
+
+// Set the don't fragment bit, datagrams that do not fit the path are dropped instead of fragmented
+#[cfg(target_os = "linux")]
+pub fn forbid_fragmentation(socket: &UdpSocket) -> io::Result<()> {
+	use std::os::fd::AsRawFd;
+
+	let (level, option, value) = match socket.local_addr()? {
+		SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO),
+		SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO),
+	};
+
+	// SAFETY: the descriptor is owned by `socket` and `value` outlives the call
+	let result = unsafe {
+		libc::setsockopt(
+			socket.as_raw_fd(),
+			level,
+			option,
+			&value as *const libc::c_int as *const libc::c_void,
+			std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+		)
+	};
+	if result != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn forbid_fragmentation(_socket: &UdpSocket) -> io::Result<()> {
+	Err(io::Error::new(io::ErrorKind::Unsupported, "cannot forbid fragmentation on this platform"))
+}
+
+// Find the largest datagram that makes it to the host and back unfragmented. Probes of
+// every size are sent at once, the host echoes each one padded to the size it arrived with.
+pub fn discover_datagram_size(socket: &UdpSocket, host_addr: SocketAddr, max_payload_size: u32) -> usize {
+	if let Err(e) = forbid_fragmentation(socket) {
+		log_info(&format!("Not probing the path MTU: {}", e));
+		return MIN_DATAGRAM_SIZE;
+	}
+
+	let max_datagram_size = max_payload_size as usize + payload_overhead();
+	let candidates: Vec<usize> = PROBE_SIZES
+		.into_iter()
+		.filter(|size| *size > MIN_DATAGRAM_SIZE && *size <= max_datagram_size)
+		.collect();
+
+	let mut buf = vec![0; 65536];
+	let mut largest = MIN_DATAGRAM_SIZE;
+	for _ in 0..PROBE_ROUNDS {
+		for &size in candidates.iter().filter(|size| **size > largest) {
+			// Sending fails outright if the size exceeds the MTU of our own interface
+			let _ = socket.send_to(&MtuProbe::padded_to(size, false).encode(), host_addr);
+		}
+
+		let deadline = Instant::now() + PROBE_TIMEOUT;
+		while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+			if socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1)))).is_err() {
+				break;
+			}
+			let (amt, src) = match socket.recv_from(&mut buf) {
+				Ok(received) => received,
+				Err(_) => break,
+			};
+			if src != host_addr {
+				continue;
+			}
+
+			if let Ok(ReditPacket::MtuProbe(probe)) = ReditPacket::decode(&buf[..amt]) {
+				if probe.echo && probe.size as usize == amt {
+					largest = largest.max(amt);
+				}
+			}
+		}
+
+		if candidates.first().is_some_and(|size| *size <= largest) {
+			break;
+		}
+	}
+
+	largest
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::Payload;
+
+	#[test]
+	fn test_payloads_fit_datagrams() {
+		for datagram_size in [MIN_DATAGRAM_SIZE, 1232, 1472, 8972] {
+			let payload_size = payload_size_for(datagram_size);
+			// Encrypted data is the payload plus its authentication tag
+			let packet = ReditPacket::Payload(Payload {
+				success: true,
+				index: u32::MAX,
+				payload_count: u32::MAX,
+				data: vec![0; payload_size as usize + 16],
+			});
+			assert!(packet.encode().len() <= datagram_size);
+		}
+		// Payloads fill the datagram, whatever their size
+		assert_eq!(payload_size_for(1472) as usize, 1472 - payload_overhead());
+		assert_eq!(payload_size_for(8972) as usize, 8972 - payload_overhead());
+
+		assert_eq!(payload_size_for(65507), PAYLOAD_SIZE);
+		assert_eq!(payload_size_for(100), MIN_PAYLOAD_SIZE);
+	}
+
+	#[test]
+	fn test_probes_have_their_size() {
+		for size in PROBE_SIZES {
+			assert_eq!(MtuProbe::padded_to(size, false).encode().len(), size);
+			assert_eq!(MtuProbe::padded_to(size, true).encode().len(), size);
+		}
+	}
+}
@@ -0,0 +1,183 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Streams a client has beyond this are dropped, its oldest first, so no client can make the
+// host queue without bound or push out the streams of others
+const MAX_STREAMS_PER_CLIENT: usize = 128;
+
+// When a stream is due next, and its turn among the streams due at the same time
+type Slot = (Instant, u64);
+
+// Payloads of a range request that still have to be sent
+struct PayloadStream {
+	src: SocketAddr,
+	session_id: u64,
+	next: u32,
+	end: u32,
+	interval: Duration,
+	slot: Slot,
+}
+
+// A payload that is due to be sent
+#[derive(Debug, PartialEq)]
+pub struct DuePayload {
+	pub src: SocketAddr,
+	pub session_id: u64,
+	pub index: u32,
+}
+
+// Spreads the payloads of range requests out at the rate every client asked for.
+// Streams take turns, so a large request does not hold back everyone else.
+#[derive(Default)]
+pub struct Pacer {
+	streams: HashMap<u64, PayloadStream>,
+	// Stream ids by when they are due, ties go to the stream served longest ago
+	schedule: BTreeSet<(Slot, u64)>,
+	// Stream ids of every client, oldest first
+	clients: HashMap<IpAddr, VecDeque<u64>>,
+	next_stream_id: u64,
+	next_turn: u64,
+}
+
+impl Pacer {
+	pub fn add(&mut self, src: SocketAddr, session_id: u64, start: u32, end: u32, interval: Duration, now: Instant) {
+		if start >= end {
+			return;
+		}
+
+		let stream_id = self.next_stream_id;
+		self.next_stream_id += 1;
+		let streams = self.clients.entry(src.ip()).or_default();
+		streams.push_back(stream_id);
+		if streams.len() > MAX_STREAMS_PER_CLIENT {
+			if let Some(oldest) = streams.pop_front() {
+				if let Some(stream) = self.streams.remove(&oldest) {
+					self.schedule.remove(&(stream.slot, oldest));
+				}
+			}
+		}
+
+		self.next_turn += 1;
+		let slot = (now, self.next_turn);
+		self.schedule.insert((slot, stream_id));
+		self.streams.insert(
+			stream_id,
+			PayloadStream {
+				src,
+				session_id,
+				next: start,
+				end,
+				interval,
+				slot,
+			},
+		);
+	}
+
+	// The next payload due at `now`
+	pub fn pop_due(&mut self, now: Instant) -> Option<DuePayload> {
+		let &((next_send, _), stream_id) = self.schedule.first().filter(|((next_send, _), _)| *next_send <= now)?;
+		self.schedule.pop_first();
+		let stream = self.streams.get_mut(&stream_id)?;
+		let due = DuePayload {
+			src: stream.src,
+			session_id: stream.session_id,
+			index: stream.next,
+		};
+
+		stream.next += 1;
+		if stream.next < stream.end {
+			self.next_turn += 1;
+			stream.slot = (next_send + stream.interval, self.next_turn);
+			self.schedule.insert((stream.slot, stream_id));
+			return Some(due);
+		}
+
+		// The stream ended
+		self.streams.remove(&stream_id);
+		let ip = due.src.ip();
+		if let Some(streams) = self.clients.get_mut(&ip) {
+			streams.retain(|other| *other != stream_id);
+			if streams.is_empty() {
+				self.clients.remove(&ip);
+			}
+		}
+		Some(due)
+	}
+
+	// The moment the next payload is due
+	pub fn next_deadline(&self) -> Option<Instant> {
+		self.schedule.first().map(|((next_send, _), _)| *next_send)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn due(src: SocketAddr, index: u32) -> Option<DuePayload> {
+		Some(DuePayload {
+			src,
+			session_id: 1,
+			index,
+		})
+	}
+
+	#[test]
+	fn test_pacing() {
+		let src: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+		let start = Instant::now();
+		let interval = Duration::from_millis(10);
+
+		let mut pacer = Pacer::default();
+		pacer.add(src, 1, 4, 7, interval, start);
+
+		assert_eq!(pacer.pop_due(start), due(src, 4));
+		assert_eq!(pacer.pop_due(start), None, "The next payload is not due yet");
+		assert_eq!(pacer.next_deadline(), Some(start + interval));
+
+		assert_eq!(pacer.pop_due(start + interval), due(src, 5));
+		assert_eq!(pacer.pop_due(start + interval * 5), due(src, 6));
+		assert_eq!(pacer.pop_due(start + interval * 5), None);
+		assert_eq!(pacer.next_deadline(), None);
+	}
+
+	#[test]
+	fn test_streams_take_turns() {
+		let first: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+		let second: SocketAddr = "10.0.0.3:4000".parse().unwrap();
+		let now = Instant::now();
+
+		let mut pacer = Pacer::default();
+		pacer.add(first, 1, 0, 100, Duration::ZERO, now);
+		pacer.add(second, 1, 0, 2, Duration::ZERO, now);
+
+		assert_eq!(pacer.pop_due(now), due(first, 0));
+		assert_eq!(pacer.pop_due(now), due(second, 0));
+		assert_eq!(pacer.pop_due(now), due(first, 1));
+		assert_eq!(pacer.pop_due(now), due(second, 1));
+		assert_eq!(pacer.pop_due(now), due(first, 2));
+		assert_eq!(pacer.pop_due(now), due(first, 3));
+	}
+
+	#[test]
+	fn test_greedy_clients_only_drop_their_own_streams() {
+		let greedy: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+		let other: SocketAddr = "10.0.0.3:4000".parse().unwrap();
+		let now = Instant::now();
+
+		let mut pacer = Pacer::default();
+		pacer.add(other, 1, 0, 1, Duration::ZERO, now);
+		for start in 0..2 * MAX_STREAMS_PER_CLIENT as u32 {
+			pacer.add(greedy, 1, start, start + 1, Duration::ZERO, now);
+		}
+
+		let mut sent = Vec::new();
+		while let Some(payload) = pacer.pop_due(now) {
+			sent.push(payload);
+		}
+		assert_eq!(sent[0], due(other, 0).unwrap());
+		assert_eq!(sent.len(), 1 + MAX_STREAMS_PER_CLIENT);
+		assert_eq!(sent[1].index, MAX_STREAMS_PER_CLIENT as u32, "The greedy client's oldest streams were dropped");
+	}
+}
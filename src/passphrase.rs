@@ -0,0 +1,128 @@
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use std::fmt;
+
+use crate::words::WORDS;
+
+pub const DEFAULT_WORD_COUNT: u8 = 6;
+pub const SEPARATOR: char = '-';
+
+// Passphrases weaker than this can be guessed offline in reasonable time, even through Argon2
+pub const MIN_ENTROPY_BITS: f64 = 50.0;
+
+#[derive(Debug, PartialEq)]
+pub enum PassphraseError {
+	Empty,
+	TooWeak { bits: f64 },
+}
+
+impl fmt::Display for PassphraseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PassphraseError::Empty => write!(f, "the passphrase is empty"),
+			PassphraseError::TooWeak { bits } => write!(
+				f,
+				"the passphrase only has about {:.0} bits of entropy, at least {:.0} are recommended",
+				bits, MIN_ENTROPY_BITS
+			),
+		}
+	}
+}
+
+// Entropy every word of a generated passphrase contributes
+pub fn word_entropy_bits() -> f64 {
+	(WORDS.len() as f64).log2()
+}
+
+// Generate a memorable passphrase of `len` uniformly chosen words
+pub fn generate_passphrase(len: u8) -> String {
+	let mut rng = OsRng;
+	(0..len)
+		.map(|_| *WORDS.choose(&mut rng).expect("The word list is empty"))
+		.collect::<Vec<&str>>()
+		.join(&SEPARATOR.to_string())
+}
+
+// Estimate the entropy of a passphrase. Passphrases made of words from the list are
+// scored by their word count, anything else by its length and the characters it uses.
+pub fn estimate_entropy(passphrase: &str) -> f64 {
+	if passphrase.is_empty() {
+		return 0.0;
+	}
+
+	let words: Vec<&str> = passphrase.split(SEPARATOR).collect();
+	if words.iter().all(|word| WORDS.contains(word)) {
+		return words.len() as f64 * word_entropy_bits();
+	}
+
+	let mut pool = 0;
+	if passphrase.chars().any(|c| c.is_ascii_lowercase()) {
+		pool += 26;
+	}
+	if passphrase.chars().any(|c| c.is_ascii_uppercase()) {
+		pool += 26;
+	}
+	if passphrase.chars().any(|c| c.is_ascii_digit()) {
+		pool += 10;
+	}
+	if passphrase.chars().any(|c| !c.is_ascii_alphanumeric()) {
+		pool += 33;
+	}
+
+	passphrase.chars().count() as f64 * (pool as f64).log2()
+}
+
+// Check that a passphrase is strong enough, returns its estimated entropy
+pub fn validate_passphrase(passphrase: &str) -> Result<f64, PassphraseError> {
+	if passphrase.is_empty() {
+		return Err(PassphraseError::Empty);
+	}
+
+	let bits = estimate_entropy(passphrase);
+	if bits < MIN_ENTROPY_BITS {
+		return Err(PassphraseError::TooWeak { bits });
+	}
+
+	Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn passphrase_range() {
+		for i in 0..255 {
+			let passphrase = generate_passphrase(i);
+			if i > 0 {
+				assert_eq!(passphrase.split(SEPARATOR).count(), i as usize);
+			}
+		}
+	}
+
+	#[test]
+	fn test_generated_passphrases_are_valid() {
+		let passphrase = generate_passphrase(DEFAULT_WORD_COUNT);
+
+		assert!(passphrase.split(SEPARATOR).all(|word| WORDS.contains(&word)));
+		assert_eq!(
+			validate_passphrase(&passphrase),
+			Ok(DEFAULT_WORD_COUNT as f64 * word_entropy_bits())
+		);
+		assert_ne!(passphrase, generate_passphrase(DEFAULT_WORD_COUNT));
+	}
+
+	#[test]
+	fn test_weak_passphrases() {
+		assert_eq!(validate_passphrase(""), Err(PassphraseError::Empty));
+		assert!(matches!(
+			validate_passphrase("hunter2"),
+			Err(PassphraseError::TooWeak { .. })
+		));
+		assert!(matches!(
+			validate_passphrase("time-people"),
+			Err(PassphraseError::TooWeak { .. })
+		));
+		assert!(validate_passphrase("Correct Horse Battery Staple 42!").is_ok());
+	}
+}
@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+// When and how often a share is served. Hosts advertise it with the share and enforce it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default, Eq, Hash)]
+pub struct Availability {
+	// Unix time in seconds from which on the share is no longer served
+	pub expires_at: Option<u64>,
+	// Complete downloads the host serves before it stops taking logins
+	pub downloads_left: Option<u32>,
+	// Time of day the share is served during
+	pub window: Option<ServeWindow>,
+}
+
+// Minutes of the day in UTC a share is served from and until, the window may span midnight
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Eq, Hash)]
+pub struct ServeWindow {
+	pub start: u16,
+	pub end: u16,
+}
+
+// Why a share is not served
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Unavailable {
+	Expired,
+	DownloadsExhausted,
+	OutsideWindow { opens_in_secs: u64 },
+}
+
+impl fmt::Display for Unavailable {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Unavailable::Expired => write!(f, "the share expired"),
+			Unavailable::DownloadsExhausted => write!(f, "the share was downloaded as often as it is served"),
+			Unavailable::OutsideWindow { opens_in_secs } => {
+				write!(f, "the share is served again in {}", format_duration(*opens_in_secs))
+			}
+		}
+	}
+}
+
+pub fn unix_now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+// "1d 2h", "3h 5m", "5m" or "30s"
+pub fn format_duration(secs: u64) -> String {
+	let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+	match (days, hours, minutes) {
+		(0, 0, 0) => format!("{}s", secs),
+		(0, 0, minutes) => format!("{}m", minutes),
+		(0, hours, minutes) => format!("{}h {}m", hours, minutes),
+		(days, hours, _) => format!("{}d {}h", days, hours),
+	}
+}
+
+// A duration such as "90s", "30m", "12h" or "7d"
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+	let value = value.trim();
+	let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+	let (count, unit) = value.split_at(split);
+	let count: u64 = count.parse().map_err(|_| format!("`{}` does not start with a number", value))?;
+	let unit_secs = match unit {
+		"s" => 1,
+		"m" => 60,
+		"h" => 3600,
+		"d" => 86400,
+		_ => return Err(format!("`{}` has no unit of s, m, h or d", value)),
+	};
+	count
+		.checked_mul(unit_secs)
+		.map(Duration::from_secs)
+		.ok_or_else(|| format!("`{}` is too long", value))
+}
+
+fn parse_time_of_day(value: &str) -> Option<u16> {
+	let (hours, minutes) = value.trim().split_once(':')?;
+	let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+	(hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+// A window such as "09:00-17:00", or "22:00-06:00" across midnight
+pub fn parse_window(value: &str) -> Result<ServeWindow, String> {
+	let window = value
+		.split_once('-')
+		.and_then(|(start, end)| Some((parse_time_of_day(start)?, parse_time_of_day(end)?)));
+	match window {
+		Some((start, end)) if start != end => Ok(ServeWindow { start, end }),
+		Some(_) => Err("the window has to end at another time than it starts".to_string()),
+		None => Err(format!("`{}` is not a window of HH:MM-HH:MM", value)),
+	}
+}
+
+impl ServeWindow {
+	// Seconds until the window opens, 0 while it is open
+	pub fn opens_in(&self, now: u64) -> u64 {
+		let minute = now / 60 % MINUTES_PER_DAY;
+		let (start, end) = (u64::from(self.start), u64::from(self.end));
+		let open = match start.cmp(&end) {
+			std::cmp::Ordering::Less => (start..end).contains(&minute),
+			std::cmp::Ordering::Greater => minute >= start || minute < end,
+			std::cmp::Ordering::Equal => true,
+		};
+		if open {
+			return 0;
+		}
+
+		let minutes = (start % MINUTES_PER_DAY + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+		(minutes * 60).saturating_sub(now % 60)
+	}
+}
+
+impl fmt::Display for ServeWindow {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{:02}:{:02}-{:02}:{:02} UTC",
+			self.start / 60,
+			self.start % 60,
+			self.end / 60,
+			self.end % 60
+		)
+	}
+}
+
+impl Availability {
+	pub fn new(expires_in: Option<Duration>, max_downloads: Option<u32>, window: Option<ServeWindow>) -> Self {
+		Availability {
+			expires_at: expires_in.map(|expires_in| unix_now().saturating_add(expires_in.as_secs())),
+			downloads_left: max_downloads,
+			window,
+		}
+	}
+
+	// Whether the share takes logins at `now`
+	pub fn check(&self, now: u64) -> Result<(), Unavailable> {
+		if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+			return Err(Unavailable::Expired);
+		}
+		if self.downloads_left == Some(0) {
+			return Err(Unavailable::DownloadsExhausted);
+		}
+		match self.window.map(|window| window.opens_in(now)) {
+			Some(opens_in_secs) if opens_in_secs > 0 => Err(Unavailable::OutsideWindow { opens_in_secs }),
+			_ => Ok(()),
+		}
+	}
+
+	// Whether sessions that already logged in are served at `now`, running out of downloads
+	// does not cut off downloads that were started
+	pub fn serves_sessions(&self, now: u64) -> Result<(), Unavailable> {
+		match self.check(now) {
+			Err(Unavailable::DownloadsExhausted) => Ok(()),
+			result => result,
+		}
+	}
+
+	// Shares that expired or ran out of downloads are never served again
+	pub fn is_over(&self, now: u64) -> bool {
+		matches!(self.check(now), Err(Unavailable::Expired | Unavailable::DownloadsExhausted))
+	}
+
+	// The limits of the share for listing it, None if it has none
+	pub fn describe(&self, now: u64) -> Option<String> {
+		let mut limits = Vec::new();
+		if let Some(expires_at) = self.expires_at {
+			match expires_at.checked_sub(now).filter(|left| *left > 0) {
+				Some(left) => limits.push(format!("expires in {}", format_duration(left))),
+				None => limits.push("expired".to_string()),
+			}
+		}
+		if let Some(downloads_left) = self.downloads_left {
+			let plural = if downloads_left == 1 { "" } else { "s" };
+			limits.push(format!("{} download{} left", downloads_left, plural));
+		}
+		if let Some(window) = self.window {
+			limits.push(format!("served {}", window));
+		}
+		(!limits.is_empty()).then(|| limits.join(", "))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// 2025-01-01 00:00 UTC
+	const MIDNIGHT: u64 = 1_735_689_600;
+
+	fn at(hours: u64, minutes: u64) -> u64 {
+		MIDNIGHT + hours * 3600 + minutes * 60
+	}
+
+	#[test]
+	fn test_serve_windows() {
+		let office = parse_window("09:00-17:30").unwrap();
+		assert_eq!(office.opens_in(at(12, 0)), 0);
+		assert_eq!(office.opens_in(at(8, 30)), 30 * 60);
+		assert_eq!(office.opens_in(at(17, 30)), 15 * 3600 + 30 * 60, "The end of the window is not part of it");
+
+		let night = parse_window("22:00-06:00").unwrap();
+		assert_eq!(night.opens_in(at(23, 0)), 0);
+		assert_eq!(night.opens_in(at(5, 59)), 0);
+		assert_eq!(night.opens_in(at(6, 0)), 16 * 3600);
+
+		assert!(parse_window("09:00-09:00").is_err());
+		assert!(parse_window("24:00-06:00").is_err());
+		assert!(parse_window("9-17").is_err());
+	}
+
+	#[test]
+	fn test_availability() {
+		let availability = Availability {
+			expires_at: Some(at(18, 0)),
+			downloads_left: Some(1),
+			window: Some(parse_window("09:00-17:00").unwrap()),
+		};
+		assert_eq!(availability.check(at(10, 0)), Ok(()));
+		assert_eq!(
+			availability.check(at(8, 0)),
+			Err(Unavailable::OutsideWindow { opens_in_secs: 3600 })
+		);
+		assert!(!availability.is_over(at(8, 0)), "A share outside its window is served again");
+		assert_eq!(availability.check(at(18, 0)), Err(Unavailable::Expired));
+		assert!(availability.is_over(at(18, 0)));
+
+		let exhausted = Availability {
+			downloads_left: Some(0),
+			..availability
+		};
+		assert_eq!(exhausted.check(at(10, 0)), Err(Unavailable::DownloadsExhausted));
+		assert_eq!(exhausted.serves_sessions(at(10, 0)), Ok(()), "Started downloads are finished");
+		assert!(exhausted.serves_sessions(at(18, 0)).is_err());
+
+		assert_eq!(
+			availability.describe(at(10, 0)).unwrap(),
+			"expires in 8h 0m, 1 download left, served 09:00-17:00 UTC"
+		);
+		assert_eq!(Availability::default().describe(at(10, 0)), None);
+	}
+
+	#[test]
+	fn test_parse_duration() {
+		assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+		assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 3600)));
+		assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+		assert!(parse_duration("7").is_err());
+		assert!(parse_duration("h").is_err());
+		assert!(parse_duration("99999999999999999999d").is_err());
+	}
+}
@@ -0,0 +1,523 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+// Which payloads of a transfer arrived, one bit per payload index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReceivedBitmap {
+	words: Vec<u64>,
+	len: u32,
+	count: u32,
+}
+
+impl ReceivedBitmap {
+	pub fn new(len: u32) -> Self {
+		ReceivedBitmap {
+			words: vec![0; (len as usize).div_ceil(64)],
+			len,
+			count: 0,
+		}
+	}
+
+	// Mark a payload as received, returns false for duplicates and out of range indices
+	pub fn insert(&mut self, index: u32) -> bool {
+		if index >= self.len || self.contains(index) {
+			return false;
+		}
+
+		self.words[index as usize / 64] |= 1 << (index % 64);
+		self.count += 1;
+		true
+	}
+
+	// Unmark a payload that has to be fetched again, returns false if it was not received
+	pub fn remove(&mut self, index: u32) -> bool {
+		if !self.contains(index) {
+			return false;
+		}
+
+		self.words[index as usize / 64] &= !(1 << (index % 64));
+		self.count -= 1;
+		true
+	}
+
+	pub fn contains(&self, index: u32) -> bool {
+		index < self.len && self.words[index as usize / 64] & (1 << (index % 64)) != 0
+	}
+
+	pub fn len(&self) -> u32 {
+		self.len
+	}
+
+	pub fn count(&self) -> u32 {
+		self.count
+	}
+
+	pub fn is_complete(&self) -> bool {
+		self.count == self.len
+	}
+}
+
+// How persistently lost payloads are requested again
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+	// Requests sent for a single payload before the transfer is given up
+	pub max_attempts: u32,
+	// Time a first request is given to be answered before round trip times are known
+	pub initial_timeout: Duration,
+	// Upper bound of the timeout, which doubles with every retry
+	pub max_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		RetryPolicy {
+			max_attempts: 8,
+			initial_timeout: Duration::from_millis(250),
+			max_timeout: Duration::from_secs(4),
+		}
+	}
+}
+
+impl RetryPolicy {
+	// Time the `attempt`th request of a payload is given to be answered, if a first one is given `timeout`
+	pub fn backoff(&self, timeout: Duration, attempt: u32) -> Duration {
+		let backoff = 1u32 << attempt.saturating_sub(1).min(16);
+		timeout.saturating_mul(backoff).min(self.max_timeout)
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RetriesExhausted {
+	pub index: u32,
+	pub attempts: u32,
+}
+
+impl fmt::Display for RetriesExhausted {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "payload {} was lost {} times", self.index, self.attempts)
+	}
+}
+
+// Payloads one host has outstanding and is to request, the rest is kept per payload
+struct Lane {
+	in_flight: BTreeSet<(Instant, u32)>,
+	lost: VecDeque<u32>,
+	// Lowest payload index that was never requested, and the end of the ones handed out
+	next_fresh: u32,
+	fresh_end: u32,
+}
+
+// Decides which payloads to request from every host, requesting lost ones again with
+// exponential backoff. What is known about a payload is kept once however many hosts
+// the transfer uses, every host only tracks the requests it has outstanding.
+pub struct Retransmitter {
+	received: ReceivedBitmap,
+	policy: RetryPolicy,
+	attempts: Vec<u32>,
+	// When every payload was last requested
+	sent: Vec<Option<Instant>>,
+	// When the outstanding request of every payload is considered lost, and the host it is outstanding at
+	deadlines: Vec<Option<(Instant, u32)>>,
+	// Hosts that were dropped have none
+	lanes: Vec<Option<Lane>>,
+	retransmissions: u64,
+}
+
+impl Retransmitter {
+	// Every host may request any payload until it is assigned a range
+	pub fn new(payload_count: u32, hosts: usize, policy: RetryPolicy) -> Self {
+		Retransmitter {
+			received: ReceivedBitmap::new(payload_count),
+			policy,
+			attempts: vec![0; payload_count as usize],
+			sent: vec![None; payload_count as usize],
+			deadlines: vec![None; payload_count as usize],
+			lanes: (0..hosts)
+				.map(|_| {
+					Some(Lane {
+						in_flight: BTreeSet::new(),
+						lost: VecDeque::new(),
+						next_fresh: 0,
+						fresh_end: payload_count,
+					})
+				})
+				.collect(),
+			retransmissions: 0,
+		}
+	}
+
+	pub fn is_active(&self, lane: usize) -> bool {
+		matches!(self.lanes.get(lane), Some(Some(_)))
+	}
+
+	// Hosts that were not dropped
+	pub fn active_lanes(&self) -> Vec<usize> {
+		(0..self.lanes.len()).filter(|lane| self.is_active(*lane)).collect()
+	}
+
+	// Record an arrived payload, whichever host it came from. Returns false if it was already received.
+	pub fn on_received(&mut self, index: u32) -> bool {
+		if !self.received.insert(index) {
+			return false;
+		}
+
+		if let Some((deadline, lane)) = self.deadlines[index as usize].take() {
+			if let Some(Some(lane)) = self.lanes.get_mut(lane as usize) {
+				lane.in_flight.remove(&(deadline, index));
+			}
+		}
+		true
+	}
+
+	// Forget that a payload arrived, it turned out to be corrupt
+	pub fn forget(&mut self, index: u32) {
+		self.received.remove(index);
+	}
+
+	// Round trip time of an arrived payload. Only payloads that were requested once tell,
+	// an answer to a retransmitted request could belong to any of its requests.
+	pub fn rtt_sample(&self, index: u32, now: Instant) -> Option<Duration> {
+		if self.attempts.get(index as usize) != Some(&1) {
+			return None;
+		}
+		self.sent[index as usize].map(|sent| now.saturating_duration_since(sent))
+	}
+
+	// Consider requests to a host that were not answered in time lost, returns how many were
+	pub fn expire(&mut self, lane: usize, now: Instant) -> u32 {
+		let Some(Some(state)) = self.lanes.get_mut(lane) else {
+			return 0;
+		};
+
+		let mut expired = 0;
+		while let Some(&(deadline, index)) = state.in_flight.first() {
+			if deadline > now {
+				break;
+			}
+			state.in_flight.pop_first();
+			self.deadlines[index as usize] = None;
+			state.lost.push_back(index);
+			expired += 1;
+		}
+		expired
+	}
+
+	// Payloads to request from a host now, keeping at most `window` requests outstanding. A
+	// first request is given `timeout` to be answered, retries are given exponentially longer.
+	pub fn next_requests(
+		&mut self,
+		lane: usize,
+		now: Instant,
+		window: usize,
+		timeout: Duration,
+	) -> Result<Vec<u32>, RetriesExhausted> {
+		self.expire(lane, now);
+		let Some(Some(state)) = self.lanes.get_mut(lane) else {
+			return Ok(Vec::new());
+		};
+
+		let mut requests = Vec::new();
+		while state.in_flight.len() < window {
+			// Lost payloads go first, the file is useless with holes in it
+			let (index, retransmission) = match state.lost.pop_front() {
+				Some(index) if self.received.contains(index) => continue,
+				Some(index) => (index, true),
+				None => match next_fresh(state, &self.received) {
+					Some(index) => (index, false),
+					None => break,
+				},
+			};
+
+			let attempts = &mut self.attempts[index as usize];
+			if *attempts >= self.policy.max_attempts {
+				// Still lost, whoever takes over the transfer has to request it
+				state.lost.push_front(index);
+				return Err(RetriesExhausted {
+					index,
+					attempts: *attempts,
+				});
+			}
+			*attempts += 1;
+			if retransmission {
+				self.retransmissions += 1;
+			}
+
+			let deadline = now + self.policy.backoff(timeout, *attempts);
+			self.sent[index as usize] = Some(now);
+			self.deadlines[index as usize] = Some((deadline, lane as u32));
+			state.in_flight.insert((deadline, index));
+			requests.push(index);
+		}
+
+		Ok(requests)
+	}
+
+	// Only request fresh payloads from `range` from a host, the others are fetched elsewhere
+	pub fn assign(&mut self, lane: usize, range: Range<u32>) {
+		let len = self.received.len();
+		if let Some(Some(state)) = self.lanes.get_mut(lane) {
+			state.next_fresh = range.start.min(len);
+			state.fresh_end = range.end.clamp(state.next_fresh, len);
+		}
+	}
+
+	// Payloads of the range assigned to a host that were not requested yet
+	pub fn unrequested(&self, lane: usize) -> Range<u32> {
+		match self.lanes.get(lane) {
+			Some(Some(state)) => state.next_fresh..state.fresh_end,
+			_ => 0..0,
+		}
+	}
+
+	// Take back the upper half of the payloads a host did not request yet
+	pub fn split_unrequested(&mut self, lane: usize) -> Range<u32> {
+		let Some(Some(state)) = self.lanes.get_mut(lane) else {
+			return 0..0;
+		};
+		let middle = state.next_fresh + (state.fresh_end - state.next_fresh) / 2;
+		let upper = middle..state.fresh_end;
+		state.fresh_end = middle;
+		upper
+	}
+
+	// Have a host request a payload before any fresh one, it was lost elsewhere. The host
+	// gets the whole retry budget for it.
+	pub fn requeue(&mut self, lane: usize, index: u32) {
+		if index >= self.received.len() || self.received.contains(index) {
+			return;
+		}
+		if let Some(Some(state)) = self.lanes.get_mut(lane) {
+			self.attempts[index as usize] = 0;
+			state.lost.push_back(index);
+		}
+	}
+
+	// Stop requesting anything from a host, returns the payloads that were requested but
+	// never arrived, and the ones that were not requested yet
+	pub fn abandon(&mut self, lane: usize) -> (Vec<u32>, Range<u32>) {
+		let Some(state) = self.lanes.get_mut(lane).and_then(Option::take) else {
+			return (Vec::new(), 0..0);
+		};
+
+		let mut pending: Vec<u32> = state.in_flight.iter().map(|(_, index)| *index).collect();
+		for index in &pending {
+			self.deadlines[*index as usize] = None;
+		}
+		pending.extend(state.lost);
+		pending.retain(|index| !self.received.contains(*index));
+		pending.sort_unstable();
+		pending.dedup();
+		(pending, state.next_fresh..state.fresh_end)
+	}
+
+	pub fn in_flight(&self, lane: usize) -> usize {
+		match self.lanes.get(lane) {
+			Some(Some(state)) => state.in_flight.len(),
+			_ => 0,
+		}
+	}
+
+	// The earliest moment an outstanding request of any host is considered lost
+	pub fn next_deadline(&self) -> Option<Instant> {
+		self.lanes
+			.iter()
+			.flatten()
+			.filter_map(|state| state.in_flight.first().map(|(deadline, _)| *deadline))
+			.min()
+	}
+
+	pub fn retransmissions(&self) -> u64 {
+		self.retransmissions
+	}
+}
+
+// The next payload of its range a host did not request and that did not arrive otherwise
+fn next_fresh(state: &mut Lane, received: &ReceivedBitmap) -> Option<u32> {
+	while state.next_fresh < state.fresh_end {
+		let index = state.next_fresh;
+		state.next_fresh += 1;
+		if !received.contains(index) {
+			return Some(index);
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TIMEOUT: Duration = Duration::from_millis(100);
+
+	fn policy() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 3,
+			initial_timeout: Duration::from_millis(100),
+			max_timeout: Duration::from_millis(300),
+		}
+	}
+
+	#[test]
+	fn test_received_bitmap() {
+		let mut received = ReceivedBitmap::new(130);
+		assert!(received.insert(0));
+		assert!(received.insert(64));
+		assert!(received.insert(129));
+		assert!(!received.insert(64), "Duplicates must not be counted twice");
+		assert!(!received.insert(130), "Indices past the end must be ignored");
+
+		assert!(received.contains(64));
+		assert!(!received.contains(63));
+		assert!(!received.contains(130));
+		assert!(!received.is_complete());
+
+		for index in 0..130 {
+			received.insert(index);
+		}
+		assert!(received.is_complete());
+
+		assert!(received.remove(64));
+		assert!(!received.remove(64));
+		assert!(!received.contains(64));
+		assert_eq!(received.count(), 129);
+	}
+
+	#[test]
+	fn test_backoff() {
+		let policy = policy();
+		assert_eq!(policy.backoff(TIMEOUT, 1), Duration::from_millis(100));
+		assert_eq!(policy.backoff(TIMEOUT, 2), Duration::from_millis(200));
+		assert_eq!(policy.backoff(TIMEOUT, 3), Duration::from_millis(300));
+		assert_eq!(policy.backoff(TIMEOUT, 40), Duration::from_millis(300));
+	}
+
+	#[test]
+	fn test_lost_payloads_are_requested_again() {
+		let mut retransmitter = Retransmitter::new(5, 1, policy());
+		let start = Instant::now();
+
+		assert_eq!(retransmitter.next_requests(0, start, 3, TIMEOUT), Ok(vec![0, 1, 2]));
+		assert_eq!(retransmitter.next_requests(0, start, 3, TIMEOUT), Ok(vec![]), "The window is full");
+
+		// Payload 1 is lost
+		assert!(retransmitter.on_received(0));
+		assert!(retransmitter.on_received(2));
+		assert!(!retransmitter.on_received(2));
+		let soon = start + Duration::from_millis(50);
+		assert_eq!(retransmitter.next_requests(0, soon, 3, TIMEOUT), Ok(vec![3, 4]));
+
+		// Lost payloads are requested before anything else once their request timed out
+		let later = start + Duration::from_millis(100);
+		assert!(retransmitter.on_received(3));
+		assert_eq!(retransmitter.next_requests(0, later, 3, TIMEOUT), Ok(vec![1]));
+		assert_eq!(retransmitter.retransmissions(), 1);
+
+		// The retry is given twice as long
+		assert_eq!(retransmitter.next_deadline(), Some(soon + Duration::from_millis(100)));
+		assert!(retransmitter.on_received(4));
+		assert_eq!(retransmitter.next_deadline(), Some(later + Duration::from_millis(200)));
+
+		assert!(retransmitter.on_received(1));
+		assert_eq!(retransmitter.in_flight(0), 0);
+		assert_eq!(retransmitter.next_requests(0, later, 3, TIMEOUT), Ok(vec![]));
+	}
+
+	#[test]
+	fn test_late_payloads_are_not_requested_again() {
+		let mut retransmitter = Retransmitter::new(2, 1, policy());
+		let start = Instant::now();
+
+		assert_eq!(retransmitter.next_requests(0, start, 1, TIMEOUT), Ok(vec![0]));
+		let later = start + Duration::from_millis(150);
+		assert_eq!(retransmitter.next_requests(0, later, 1, TIMEOUT), Ok(vec![0]));
+
+		// The first answer arrives after all, the retry was only for payload 0
+		assert!(retransmitter.on_received(0));
+		assert_eq!(retransmitter.next_requests(0, later, 1, TIMEOUT), Ok(vec![1]));
+	}
+
+	#[test]
+	fn test_retry_budget() {
+		let mut retransmitter = Retransmitter::new(1, 1, policy());
+		let mut now = Instant::now();
+
+		for _ in 0..3 {
+			assert_eq!(retransmitter.next_requests(0, now, 1, TIMEOUT), Ok(vec![0]));
+			now += Duration::from_secs(1);
+		}
+		assert_eq!(
+			retransmitter.next_requests(0, now, 1, TIMEOUT),
+			Err(RetriesExhausted { index: 0, attempts: 3 })
+		);
+	}
+
+	#[test]
+	fn test_assigned_ranges() {
+		let mut retransmitter = Retransmitter::new(10, 1, policy());
+		let start = Instant::now();
+		retransmitter.on_received(4);
+		retransmitter.assign(0, 2..8);
+
+		assert_eq!(retransmitter.next_requests(0, start, 2, TIMEOUT), Ok(vec![2, 3]));
+		assert_eq!(retransmitter.unrequested(0), 4..8);
+		assert_eq!(retransmitter.split_unrequested(0), 6..8);
+
+		// Payloads lost elsewhere go first, already received ones are skipped
+		retransmitter.requeue(0, 9);
+		assert!(retransmitter.on_received(2));
+		assert_eq!(retransmitter.next_requests(0, start, 4, TIMEOUT), Ok(vec![9, 5]));
+		assert_eq!(retransmitter.next_requests(0, start, 8, TIMEOUT), Ok(vec![]));
+
+		assert_eq!(retransmitter.abandon(0), (vec![3, 5, 9], 6..6));
+		assert_eq!(retransmitter.in_flight(0), 0);
+		assert_eq!(retransmitter.next_deadline(), None);
+	}
+
+	#[test]
+	fn test_hosts_share_payload_state() {
+		let mut retransmitter = Retransmitter::new(6, 2, policy());
+		let start = Instant::now();
+		retransmitter.assign(0, 0..3);
+		retransmitter.assign(1, 3..6);
+
+		assert_eq!(retransmitter.next_requests(0, start, 2, TIMEOUT), Ok(vec![0, 1]));
+		assert_eq!(retransmitter.next_requests(1, start, 2, TIMEOUT), Ok(vec![3, 4]));
+
+		// An answer settles the request of the host it was outstanding at
+		assert!(retransmitter.on_received(3));
+		assert_eq!(retransmitter.in_flight(0), 2);
+		assert_eq!(retransmitter.in_flight(1), 1);
+
+		// A payload lost at one host is handed to the other with a fresh retry budget
+		let later = start + Duration::from_millis(150);
+		assert_eq!(retransmitter.expire(1, later), 1);
+		assert_eq!(retransmitter.abandon(1), (vec![4], 5..6));
+		assert!(!retransmitter.is_active(1));
+		retransmitter.requeue(0, 4);
+		assert!(retransmitter.on_received(0));
+		assert!(retransmitter.on_received(1));
+		assert_eq!(retransmitter.next_requests(0, later, 2, TIMEOUT), Ok(vec![4, 2]));
+		assert_eq!(retransmitter.rtt_sample(4, later), Some(Duration::ZERO));
+		assert_eq!(retransmitter.active_lanes(), vec![0]);
+	}
+
+	#[test]
+	fn test_rtt_samples() {
+		let mut retransmitter = Retransmitter::new(2, 1, policy());
+		let start = Instant::now();
+
+		assert_eq!(retransmitter.next_requests(0, start, 2, TIMEOUT), Ok(vec![0, 1]));
+		let later = start + Duration::from_millis(150);
+		assert_eq!(retransmitter.expire(0, later), 2);
+		assert_eq!(retransmitter.expire(0, later), 0);
+		assert_eq!(retransmitter.next_requests(0, later, 1, TIMEOUT), Ok(vec![0]));
+
+		// Payload 1 was requested once, payload 0 twice
+		let arrival = later + Duration::from_millis(10);
+		assert_eq!(retransmitter.rtt_sample(1, arrival), Some(Duration::from_millis(160)));
+		assert_eq!(retransmitter.rtt_sample(0, arrival), None);
+	}
+}
@@ -4,7 +4,7 @@ use std::thread;
 use std::sync::mpsc;
 use std::collections::HashSet;
 use std::net::{UdpSocket, IpAddr, Ipv4Addr, SocketAddr};
-use crate::types::{ReditPacket, ScanStore, RequestScanStore, UploaderInfo, RequestUploaderInfo};
+use crate::types::{PacketError, ReditPacket, ScanStore, RequestScanStore, UploaderInfo, RequestUploaderInfo, RequestCatalog};
 use std::io;
 use std::fs;
 use std::sync;
@@ -13,21 +13,46 @@ use std::io::Write;
 use std::time::Duration;
 use crate::utils::get_local_ip;
 use crate::utils::{cancellation_token, CancellationToken};
-use crate::logger::{log_error, log_info};
+use crate::logger::{log_error, log_info, log_warning};
+use crate::encryption::{sign, verify_signature};
+use rsa::RsaPrivateKey;
 
 const PORT: u16 = 6969;
 
-pub fn resolve_packet(packet: ReditPacket, address_channel: mpsc::Sender<Option<IpAddr>>, uploader_channel: mpsc::Sender<Option<(UploaderInfo, IpAddr)>>, address: SocketAddr) {
+pub fn resolve_packet(socket: &UdpSocket, packet: ReditPacket, address_channel: mpsc::Sender<Option<IpAddr>>, uploader_channel: mpsc::Sender<Option<(UploaderInfo, IpAddr)>>, address: SocketAddr) {
 	log_info(&format!("<- {:?}", packet));
 	match packet {
 		ReditPacket::ScanStore(scan_store) => {
+			// Only follow hints from hosts that are who they claim to be
+			let Some(public_key) = scan_store.public_key.as_deref() else {
+				log_warning(&format!("Ignoring unsigned scan store from {}", address));
+				return;
+			};
+			if !verify_signature(public_key, &scan_store.signed_bytes(), &scan_store.signature) {
+				log_warning(&format!("Ignoring forged scan store from {}", address));
+				return;
+			}
+			/* The respondee is a peer itself. */
+			address_channel.send(Some(address.ip()));
 			for record in scan_store.store.iter() {
 				address_channel.send(Some(*record));
 			}
 		}
+		ReditPacket::Catalog(catalog) => {
+			/* Ask for the announcement of every share, and for the rest of the catalog. */
+			for entry in catalog.shares.iter() {
+				request_uploader_info(socket, address, entry.share_id);
+			}
+			if let Some(start) = catalog.next {
+				request_catalog(socket, address, start);
+			}
+		}
 		ReditPacket::UploaderInfo(uploader) => {
 			uploader_channel.send(Some((uploader, address.ip())));
 		}
+		ReditPacket::Reject(reject) => {
+			log_warning(&format!("{} refused to talk to us: {}", address, reject.reason));
+		}
 		_ => { }
 	}
 }
@@ -35,19 +60,27 @@ pub fn resolve_packet(packet: ReditPacket, address_channel: mpsc::Sender<Option<
 pub fn request_packet(socket: &UdpSocket, addr: SocketAddr) {
 	let packet = ReditPacket::RequestScanStore(RequestScanStore {});
 
-	let _ = socket.send_to(&bincode::serialize(&packet).unwrap(), addr);
+	let _ = socket.send_to(&packet.encode(), addr);
 }
 
-pub fn request_uploader_info(socket: &UdpSocket, addr: SocketAddr) {
+pub fn request_catalog(socket: &UdpSocket, addr: SocketAddr, start: u32) {
+	let packet = ReditPacket::RequestCatalog(RequestCatalog { start });
+
+	let _ = socket.send_to(&packet.encode(), addr);
+}
+
+pub fn request_uploader_info(socket: &UdpSocket, addr: SocketAddr, share_id: u32) {
 	let packet = ReditPacket::RequestUploaderInfo(RequestUploaderInfo {
 		public_key: Some("".to_string()),
+		share_id,
 	});
 
-	let _ = socket.send_to(&bincode::serialize(&packet).unwrap(), addr);
+	let _ = socket.send_to(&packet.encode(), addr);
 }
 
 pub fn scan_receive(socket: &UdpSocket, address_channel: mpsc::Sender<Option<IpAddr>>, uploader_channel: mpsc::Sender<Option<(UploaderInfo, IpAddr)>>, terminate: &CancellationToken) {
-	let mut buf = [0; 1024];
+	/* Catalogs fill a whole datagram. */
+	let mut buf = [0; 65536];
 
 	socket.set_read_timeout(Some(Duration::from_millis(10)));
 	loop {
@@ -55,9 +88,12 @@ pub fn scan_receive(socket: &UdpSocket, address_channel: mpsc::Sender<Option<IpA
 			return;
 		}
 		match socket.recv_from(&mut buf) {
-			Ok((_response_size, respondee_address)) => match bincode::deserialize::<ReditPacket>(&buf) {
+			Ok((response_size, respondee_address)) => match ReditPacket::decode(&buf[..response_size]) {
 				Ok(res) => {
-					resolve_packet(res, address_channel.clone(), uploader_channel.clone(), respondee_address);
+					resolve_packet(socket, res, address_channel.clone(), uploader_channel.clone(), respondee_address);
+				}
+				Err(e @ PacketError::VersionMismatch { .. }) => {
+					log_warning(&format!("Ignoring incompatible host {}: {}", respondee_address, e));
 				}
 				Err(e) => {
 					log_error(&format!("Failed to deserialize packet: {}", e));
@@ -87,9 +123,9 @@ pub fn scan(uploader_channel: mpsc::Sender<Option<(UploaderInfo, IpAddr)>>) {
 	uploader_channel.send(None);
 }
 
-pub fn submit_scan_store(socket: &UdpSocket, addr: SocketAddr) {
+pub fn submit_scan_store(socket: &UdpSocket, addr: SocketAddr, identity: &RsaPrivateKey, public_key: Option<String>) {
 	log_info("Submitting scan store");
-	let scan_store_persistent = match fs::OpenOptions::new().create(true).read(true).open("scan_store.txt") {
+	let scan_store_persistent = match fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open("scan_store.txt") {
 		Ok(file) => file,
 		Err(_) => return
 	};
@@ -108,11 +144,32 @@ pub fn submit_scan_store(socket: &UdpSocket, addr: SocketAddr) {
 		scan_store_staging.insert(addr);
 	}
 
-	let packet = ReditPacket::ScanStore(ScanStore {
+	let mut scan_store = ScanStore {
 		store: scan_store_staging,
-	});
+		public_key,
+		signature: Vec::new(),
+	};
+	scan_store.signature = sign(identity, &scan_store.signed_bytes());
 
-	let _ = socket.send_to(&bincode::serialize(&packet).unwrap(), addr);
+	let packet = ReditPacket::ScanStore(scan_store);
+
+	let _ = socket.send_to(&packet.encode(), addr);
+}
+
+/* Ask every newly discovered peer for its catalog, while the listener is still running. */
+fn request_discovered(socket: &UdpSocket, address_channel_rx: &mpsc::Receiver<Option<IpAddr>>, discovered: &mut HashSet<IpAddr>) -> Vec<IpAddr> {
+	let mut new_records = Vec::new();
+	while let Ok(Some(record)) = address_channel_rx.recv_timeout(Duration::from_millis(10)) {
+		if !discovered.insert(record) {
+			continue;
+		}
+		request_catalog(socket, SocketAddr::new(record, PORT), 0);
+		new_records.push(record);
+	}
+
+	/* Allow leeway for the uploader infos to arrive. */
+	thread::sleep(Duration::from_millis(1000));
+	new_records
 }
 
 pub fn scan_efficient(socket: UdpSocket, uploader_channel: mpsc::Sender<Option<(UploaderInfo, IpAddr)>>, depth: u32) {
@@ -129,46 +186,44 @@ pub fn scan_efficient(socket: UdpSocket, uploader_channel: mpsc::Sender<Option<(
 		scan_receive(&recipient_socket, address_channel_tx, recipient_uploader_channel, &terminate);
 	});
 
-	let scan_store_persistent = match fs::OpenOptions::new().create(true).read(true).open("scan_store.txt") {
+	let scan_store_persistent = match fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open("scan_store.txt") {
 		Ok(file) => file,
 		Err(_) => return
 	};
 
+	let mut known_records: HashSet<IpAddr> = Default::default();
 	let scan_store_reader = io::BufReader::new(scan_store_persistent);
 	for line in scan_store_reader.lines() {
 		let line = match line {
 			Ok(line) => line,
 			Err(_) => return
 		};
-		let addr: SocketAddr = match line.parse() {
-			Ok(addr) => addr,
-			Err(_) => return
+		let record: IpAddr = match line.parse() {
+			Ok(record) => record,
+			Err(_) => continue
 		};
-		request_packet(&socket, addr);
+		known_records.insert(record);
+		request_packet(&socket, SocketAddr::new(record, PORT));
 	}
 
 	/* Allow leeway for respondees to respond. */
 	thread::sleep(Duration::from_millis(2000));
+
+	let mut discovered: HashSet<IpAddr> = Default::default();
+	let new_records = request_discovered(&socket, &address_channel_rx, &mut discovered);
 	terminator.cancel();
+	recipient.join().unwrap();
 
-	let mut scan_store_staging: HashSet<IpAddr> = Default::default();
-	let mut scan_store_persistent = match fs::OpenOptions::new().write(true).open("scan_store.txt") {
+	let mut scan_store_persistent = match fs::OpenOptions::new().append(true).open("scan_store.txt") {
 		Ok(file) => file,
 		Err(_) => return
 	};
-
-	while let Ok(Some(record)) = address_channel_rx.recv() {
-		scan_store_staging.insert(record);
-		if scan_store_staging.contains(&record) {
-			continue;
+	for record in new_records {
+		if !known_records.contains(&record) {
+			writeln!(scan_store_persistent, "{}", record);
 		}
-		let socket_address: SocketAddr = SocketAddr::new(record.into(), PORT);
-		request_uploader_info(&socket, socket_address);
-		writeln!(scan_store_persistent, "{}", record.to_string());
 	}
 
-	recipient.join().unwrap();
-
 	scan_efficient(socket, uploader_channel, depth - 1);
 }
 
@@ -183,13 +238,11 @@ pub fn scan_iterative(socket: UdpSocket, uploader_channel: mpsc::Sender<Option<(
 	};
 
 	/* Set up listener thread. */
-	let (tx, rx) = mpsc::channel::<Option<IpAddr>>();
 	let (address_channel_tx, address_channel_rx) = mpsc::channel::<Option<IpAddr>>();
 	let recipient_socket = socket.try_clone().unwrap();
-	let recipient_address_channel_tx = address_channel_tx.clone();
 	let (terminator, terminate) = cancellation_token();
 	let recipient = thread::spawn(move || {
-		scan_receive(&recipient_socket, recipient_address_channel_tx, uploader_channel, &terminate);
+		scan_receive(&recipient_socket, address_channel_tx, uploader_channel, &terminate);
 	});
 
 	if local_ip.is_ipv4() {
@@ -203,25 +256,19 @@ pub fn scan_iterative(socket: UdpSocket, uploader_channel: mpsc::Sender<Option<(
 				let addr: SocketAddr = SocketAddr::new(ip.into(), PORT);
 
 				request_packet(&socket, addr);
-				print!("Scanning {}\r", addr.to_string());
+				print!("Scanning {}\r", addr);
 			}
 			thread::sleep(Duration::from_millis(100));
 		}
-		println!("");
+		println!();
 	}
 
 	thread::sleep(Duration::from_millis(2000));
-	terminator.cancel();
-	recipient.join().unwrap();
 
 	let mut scan_store_staging: HashSet<IpAddr> = Default::default();
-	while let Ok(Some(record)) = rx.recv_timeout(Duration::from_millis(10)) {
-		address_channel_tx.send(Some(record));
-		scan_store_staging.insert(record);
-		let socket_address: SocketAddr = SocketAddr::new(record.into(), PORT);
-		request_uploader_info(&socket, socket_address);
-	}
+	request_discovered(&socket, &address_channel_rx, &mut scan_store_staging);
+	terminator.cancel();
+	recipient.join().unwrap();
 
-	return scan_store_staging;
+	scan_store_staging
 }
-
@@ -1,254 +1,702 @@
+use crate::catalog::{HostProfile, HostedShare, Shares};
+use crate::control::{self, ControlRequest, ControlResponse, ControlSocket, HostStatus};
 use crate::encryption::{
-	derive_key, encrypt_with_passphrase, generate_private_key, generate_public_key, generate_salt,
-	public_key_to_string,
+	client_confirmation, derive_traffic_key, finish_login, generate_ephemeral_key, identity_fingerprint, server_confirmation, sign,
+	start_server_login, PayloadCipher,
 };
-use crate::logger::{log_error, log_info};
+use crate::logger::{log_error, log_info, log_success, log_warning};
+use crate::mtu::{forbid_fragmentation, MIN_DATAGRAM_SIZE, MIN_PAYLOAD_SIZE};
+use crate::pacer::{DuePayload, Pacer};
+use crate::passphrase::{generate_passphrase, validate_passphrase};
+use crate::policy::{unix_now, Availability};
 use crate::scan;
+use crate::session::{Session, Sessions};
 use crate::types;
-use crate::types::Payload;
-use crate::types::UploaderInfo;
-use crate::types::PAYLOAD_SIZE;
-use rsa::{Pkcs1v15Encrypt, RsaPrivateKey};
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom};
+use crate::types::{
+	Capabilities, DownloadFinished, FecParams, Hello, KdfParams, LoginChallenge, LoginConfirm, LoginRequest, LoginResult, MtuProbe, PacketError,
+	NodeHashes, Payload, Reject, RejectReason, ReditPacket, RequestCatalog, RequestNodeHashes, RequestPayloadRange, RequestUploaderInfo,
+	UploaderInfo,
+};
+use crate::types::{MAX_RANGE_LENGTH, PAYLOAD_SIZE};
+use crate::workers::WorkerPool;
+use std::io;
 use std::net::{SocketAddr, UdpSocket};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Slowest pace a range request is served at
+const MAX_PACING_INTERVAL: Duration = Duration::from_millis(100);
+
+// Requests are handled by at least this many workers, more on machines with more cores
+const MIN_WORKERS: usize = 4;
+// Requests a client may have queued, enough for the payloads of a couple of range requests
+const MAX_QUEUED_JOBS: usize = 2 * MAX_RANGE_LENGTH as usize;
+// Requests all clients together may have queued, new ones are dropped beyond
+const MAX_QUEUED_JOBS_TOTAL: usize = 16 * MAX_QUEUED_JOBS;
+
+pub fn host(
+	is_public: bool,
+	paths: Vec<(PathBuf, Availability)>,
+	name: String,
+	password: Option<String>,
+	word_count: u8,
+	kdf_params: KdfParams,
+	daemon: bool,
+) {
+	let password = if is_public {
+		if password.as_deref().is_some_and(|password| !password.trim().is_empty()) {
+			log_warning("Public shares are readable by everyone, ignoring the passphrase");
+		}
+		String::new()
+	} else {
+		match resolve_passphrase(password, word_count) {
+			Some(password) => password,
+			None => return,
+		}
+	};
 
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use tar::Builder;
-
-// Read a chunk of a file starting from `start` ending at `end`
-fn read_file_chunk(file_path: &Path, start: u64, end: u64) -> io::Result<Vec<u8>> {
-	let mut file = File::open(file_path).unwrap();
+	// A daemon takes commands from `redit share` and `redit status` while it hosts
+	let control = if daemon {
+		let path = control::socket_path();
+		match ControlSocket::bind(&path) {
+			Ok(control) => {
+				log_info(&format!("Taking commands on {}", path.display()));
+				Some(control)
+			}
+			Err(e) => {
+				log_error(&format!("Failed to open the control socket {}: {}", path.display(), e));
+				return;
+			}
+		}
+	} else {
+		None
+	};
 
-	file.seek(SeekFrom::Start(start)).unwrap();
+	let Some(profile) = HostProfile::new(is_public, name, &password, kdf_params) else {
+		return;
+	};
 
-	let chunk_size = (end - start) as usize;
-	let mut buffer = vec![0; chunk_size];
-	file.read_exact(&mut buffer).unwrap();
+	// Every path is a share of the host's catalog
+	let mut shares = Shares::default();
+	for (path, availability) in &paths {
+		match profile.share_path(shares.next_id(), path, *availability) {
+			Ok(share) => {
+				log_info(&format!("Sharing {} as share {}", path.display(), share.info.share_id));
+				shares.insert(share);
+			}
+			Err(e) => {
+				log_error(&format!("Failed to share {}: {}", path.display(), e));
+				return;
+			}
+		}
+	}
 
-	Ok(buffer)
+	start_listener(profile, shares, control)
 }
 
-pub fn host(is_public: bool, file_path_buf: PathBuf, name: String, password: Option<String>) {
+// The passphrase to host with, a memorable one is generated when none was given. None if
+// the generated one is too weak.
+pub fn resolve_passphrase(password: Option<String>, word_count: u8) -> Option<String> {
 	// Trim the password
 	let password = password.as_deref().unwrap_or("").trim().to_string();
+	if !password.is_empty() {
+		if let Err(e) = validate_passphrase(&password) {
+			log_warning(&format!("Weak passphrase: {}", e));
+		}
+		return Some(password);
+	}
 
-	// Generate private and public key
-	let private = generate_private_key();
-	let public = generate_public_key(private.clone());
-
-	// Turn the file path buffer to a Path
-	let mut file_path = file_path_buf.as_path();
+	let password = generate_passphrase(word_count);
+	match validate_passphrase(&password) {
+		Ok(bits) => {
+			log_success(&format!("Passphrase: {}", password));
+			log_info(&format!("The passphrase has {:.0} bits of entropy", bits));
+			Some(password)
+		}
+		Err(e) => {
+			log_error(&format!("Refusing to use a generated passphrase: {}", e));
+			log_error("Use more words");
+			None
+		}
+	}
+}
 
-	// Specify packaging type as Tarred if file is a directory
-	let packaging_type = if file_path.is_dir() {
-		types::PackagingType::Tarred
-	} else {
-		types::PackagingType::None
+// Share a completed download under `name`. It is announced as the content it was downloaded
+// as, so clients fetch it from this host and the ones it came from alike.
+pub fn seed(file_path: &Path, content: &UploaderInfo, name: String, password: &str) {
+	let Some(profile) = HostProfile::new(content.public, name, password, content.kdf_params) else {
+		return;
 	};
 
-	let info = UploaderInfo {
-		public: is_public,
-		name,
-		file_name: file_path.file_name().unwrap().to_string_lossy().to_string(),
-		packaging: packaging_type,
-		files_size: 3,
-		public_key: Some(public_key_to_string(public)),
-		hashed_connection_salt: None,
+	let mut shares = Shares::default();
+	// The seed stops serving when the share it was downloaded from does, its downloads are its own
+	let availability = Availability {
+		downloads_left: None,
+		..content.availability
 	};
-
-	let tar_path = format!(
-		"./tars/{}.tar.gz",
-		file_path.file_stem().unwrap().to_string_lossy()
+	let share = profile.open_share(
+		shares.next_id(),
+		file_path,
+		content.file_name.clone(),
+		content.packaging.clone(),
+		availability,
 	);
+	match share {
+		Ok(share) if share.info.merkle_root == content.merkle_root => shares.insert(share),
+		Ok(_) => {
+			log_error(&format!("{} changed since it was downloaded, not sharing it", file_path.display()));
+			return;
+		}
+		Err(e) => {
+			log_error(&format!("Failed to share {}: {}", file_path.display(), e));
+			return;
+		}
+	}
+
+	log_info(&format!("Sharing {} as {}", file_path.display(), profile.name));
+	start_listener(profile, shares, None)
+}
+
+// Carry out a command of `redit share` or `redit status`, they are handled one at a time
+fn on_control_request(host: &Host, request: ControlRequest) -> ControlResponse {
+	match request {
+		ControlRequest::AddShare { path, availability } => add_share(host, &path, availability),
+		ControlRequest::RemoveShare { share_id } => remove_share(host, share_id),
+		ControlRequest::ListShares => {
+			ControlResponse::Shares(host.shares.read().unwrap().iter().map(|share| share.entry()).collect())
+		}
+		ControlRequest::RotatePassphrase { passphrase } => rotate_passphrase(host, &passphrase),
+		ControlRequest::Status => {
+			let profile = host.profile.read().unwrap();
+			ControlResponse::Status(HostStatus {
+				name: profile.name.clone(),
+				identity: identity_fingerprint(&profile.public_key),
+				shares: host.shares.read().unwrap().iter().count(),
+				clients: host.sessions.lock().unwrap().clients(),
+			})
+		}
+	}
+}
 
-	// If it is a directory set the tar path as file path
-	if file_path.is_dir() {
-		tar_dir(file_path.to_string_lossy().into_owned(), tar_path.clone());
-		file_path = Path::new(&tar_path)
+fn add_share(host: &Host, path: &Path, availability: Availability) -> ControlResponse {
+	let share_id = host.shares.write().unwrap().next_id();
+	// The file is hashed with a copy of the profile, so logins go on meanwhile
+	let profile = host.profile.read().unwrap().clone();
+	match profile.share_path(share_id, path, availability) {
+		Ok(share) => {
+			host.shares.write().unwrap().insert(share);
+			log_info(&format!("Sharing {} as share {}", path.display(), share_id));
+			ControlResponse::ShareAdded { share_id }
+		}
+		Err(e) => {
+			log_error(&format!("Failed to share {}: {}", path.display(), e));
+			ControlResponse::Failed(format!("Failed to share {}: {}", path.display(), e))
+		}
 	}
+}
+
+// Stop sharing, the clients downloading the share are logged out
+fn remove_share(host: &Host, share_id: u32) -> ControlResponse {
+	let removed = host.shares.write().unwrap().remove(share_id);
+	let Some(share) = removed else {
+		return ControlResponse::Failed(format!("There is no share {}", share_id));
+	};
 
-	start_listener(info, &file_path, Some(password), private)
+	host.sessions.lock().unwrap().end_share(share_id);
+	if let Err(e) = share.discard() {
+		log_warning(&format!("Failed to remove the tarball of share {}: {}", share_id, e));
+	}
+	log_info(&format!("No longer sharing {} (share {})", share.info.file_name, share_id));
+	ControlResponse::ShareRemoved
 }
 
-// Make a tar of the directory
+// Announce the shares with a new salt, logins from then on need the new passphrase
+fn rotate_passphrase(host: &Host, passphrase: &str) -> ControlResponse {
+	let current = host.profile.read().unwrap().clone();
+	if current.public {
+		return ControlResponse::Failed("Public shares have no passphrase".to_string());
+	}
+	// The key is derived while logins still use the old one
+	let Some(rotated) = current.with_passphrase(passphrase) else {
+		return ControlResponse::Failed("Failed to derive the share key".to_string());
+	};
 
-fn tar_dir(file_path: String, tar_path: String) {
-	let tar_gz = File::create(tar_path.clone()).unwrap();
-	let enc = GzEncoder::new(tar_gz, Compression::default());
-	let mut tar = Builder::new(enc);
+	// The shares are signed before the catalog is locked, it is only locked to swap them in
+	let shares: Vec<Arc<HostedShare>> = host.shares.read().unwrap().iter().cloned().collect();
+	let announced: Vec<HostedShare> = shares
+		.iter()
+		.map(|share| rotated.reannounce(share, share.info.availability))
+		.collect();
+	let mut changed = Vec::new();
+	{
+		let mut profile = host.profile.write().unwrap();
+		let mut catalog = host.shares.write().unwrap();
+		for (share, announced) in shares.iter().zip(announced) {
+			if !catalog.replace(share, announced) {
+				changed.push(share.info.share_id);
+			}
+		}
+		*profile = rotated;
+	}
 
-	tar.append_dir_all(".", file_path).unwrap();
-	tar.finish().unwrap();
+	// Shares that were counted while they were signed carry the old salt, they are signed again
+	for share_id in changed {
+		reannounce_share(host, share_id, |availability| availability);
+	}
+	log_info("The shares have a new passphrase");
+	ControlResponse::PassphraseRotated
 }
 
-fn on_request_uploader_info(
-	socket: UdpSocket,
-	src: SocketAddr,
-	uploader_info: UploaderInfo,
-	salt_mappings: &mut std::collections::HashMap<SocketAddr, String>,
-	file_size: u64,
-) {
-	let salt = salt_mappings
-		.entry(src)
-		.or_insert_with(generate_salt)
-		.clone();
+// Describe one share of the catalog
+fn on_request_uploader_info(host: &Host, src: SocketAddr, request: RequestUploaderInfo) {
+	let share = host.shares.read().unwrap().get(request.share_id);
+	let Some(share) = share.filter(|share| !share.info.availability.is_over(unix_now())) else {
+		log_warning(&format!("{} asked for share {}, which is not shared", src, request.share_id));
+		return;
+	};
 
-	// Update uploader_info with the salt
-	let mut local_uploader_info = uploader_info;
-	local_uploader_info.hashed_connection_salt = Some(salt);
-	local_uploader_info.files_size = file_size;
+	let serialized = ReditPacket::UploaderInfo(share.info.clone()).encode();
+	if host.socket.send_to(&serialized, src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
 
-	let serialized = bincode::serialize(&local_uploader_info).unwrap();
-	socket
-		.send_to(&serialized, src)
-		.expect("Couldn't send data");
+// List the shares from the requested one on, as many as fit a datagram every path carries
+fn on_request_catalog(host: &Host, src: SocketAddr, request: RequestCatalog) {
+	let catalog = host.shares.read().unwrap().page(request.start, MIN_DATAGRAM_SIZE, unix_now());
+	if host.socket.send_to(&ReditPacket::Catalog(catalog).encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
 }
 
-fn on_request_payload(
-	socket: UdpSocket,
-	src: SocketAddr,
-	private_key: RsaPrivateKey,
-	hashed_password: Vec<u8>,
-	password: Option<String>,
-	chunk_count: u64,
-	payload_index: u32,
-	file_size: u64,
-	file_path: &Path,
-) {
-	// Decrypt the hashed password from the client
-	let decrypted_key = match private_key.decrypt(Pkcs1v15Encrypt, &hashed_password) {
-		Ok(key) => key,
-		Err(_) => {
-			log_error("Failed to decrypt password");
-			return;
+// Answer a Hello with the capabilities both peers share, or reject the peer
+fn on_hello(socket: &UdpSocket, src: SocketAddr, hello: Hello) {
+	let response = match Capabilities::local().negotiate(&hello.capabilities) {
+		Ok(capabilities) => ReditPacket::Capabilities(capabilities),
+		Err(reason) => {
+			log_warning(&format!("Rejecting {}: {}", src, reason));
+			ReditPacket::Reject(Reject { reason })
 		}
 	};
 
-	let decrypted_password = match String::from_utf8(decrypted_key) {
-		Ok(password) => password,
-		Err(_) => {
-			log_error("Failed to create password string");
-			return;
+	if socket.send_to(&response.encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
+
+fn on_undecodable_packet(socket: &UdpSocket, src: SocketAddr, error: PacketError) {
+	log_error(&format!("Received undeserializable packet from {}: {}", src, error));
+
+	// Tell peers running another protocol version why they are not being served
+	if let PacketError::VersionMismatch { expected, received } = error {
+		let response = ReditPacket::Reject(Reject {
+			reason: types::RejectReason::VersionMismatch { expected, received },
+		});
+		if socket.send_to(&response.encode(), src).is_err() {
+			log_error("Couldn't send data");
 		}
+	}
+}
+
+// Answer the client's SPAKE2 message and ephemeral key
+fn on_login_request(host: &Host, src: SocketAddr, request: LoginRequest) {
+	let Some(share) = host.shares.read().unwrap().get(request.share_id) else {
+		log_warning(&format!("{} tried to log in to share {}, which is not shared", src, request.share_id));
+		return;
 	};
+	// Tell the client why, it would otherwise wait for an answer in vain
+	if let Err(reason) = share.info.availability.check(unix_now()) {
+		log_warning(&format!("Refusing login from {}: {}", src, reason));
+		let response = ReditPacket::Reject(Reject {
+			reason: RejectReason::Unavailable(reason),
+		});
+		if host.socket.send_to(&response.encode(), src).is_err() {
+			log_error("Couldn't send data");
+		}
+		return;
+	}
 
-	// Check if a password is provided by the host
-	let password_ref = match password.as_ref() {
-		Some(p) => p,
+	let share_key = host.profile.read().unwrap().share_key;
+	let (state, spake_message) = start_server_login(&share_key);
+	let (ephemeral_secret, ephemeral_public) = generate_ephemeral_key();
+	let session_key = match finish_login(state, &request.spake_message).and_then(|login_key| {
+		derive_traffic_key(
+			&login_key,
+			ephemeral_secret,
+			&request.ephemeral_public,
+			&ephemeral_public,
+			&request.ephemeral_public,
+		)
+	}) {
+		Some(key) => key,
 		None => {
-			log_error("You must provide a password!");
+			log_error(&format!("Received a malformed login from {}", src));
 			return;
 		}
 	};
 
-	// Validate the clients password
-	if decrypted_password != *password_ref {
-		log_error("Wrong password");
+	// The client sizes payloads to fit its path, within what the host is willing to send
+	let payload_size = request.payload_size.clamp(MIN_PAYLOAD_SIZE, PAYLOAD_SIZE);
+	let cipher = PayloadCipher::new(&session_key, share.info.nonce_seed);
+	let fec = request.fec.filter(FecParams::is_acceptable);
+	let session_id = host.sessions.lock().unwrap().insert(
+		request.share_id,
+		src,
+		cipher,
+		payload_size,
+		fec,
+		client_confirmation(&session_key),
+	);
+	let mut challenge = LoginChallenge {
+		session_id,
+		spake_message,
+		ephemeral_public,
+		confirmation: server_confirmation(&session_key),
+		payload_size,
+		fec,
+		signature: Vec::new(),
+	};
+	challenge.signature = sign(&host.profile.read().unwrap().identity, &challenge.signed_bytes(&request));
+	let response = ReditPacket::LoginChallenge(challenge);
 
-		// Send back success false if the password is not provided
-		let response_payload = Payload {
-			success: false,
-			index: 0,
-			payload_count: 0,
-			data: Vec::new(),
-		};
+	if host.socket.send_to(&response.encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
+
+fn on_login_confirm(host: &Host, src: SocketAddr, confirm: LoginConfirm) {
+	let success = host.sessions.lock().unwrap().confirm(confirm.session_id, confirm.confirmation);
+	if !success {
+		log_error(&format!("Wrong password from {}", src));
+	}
+
+	let response = ReditPacket::LoginResult(LoginResult {
+		session_id: confirm.session_id,
+		success,
+	});
+
+	if host.socket.send_to(&response.encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
+
+// Echo a probe back at the size it arrived with, so the client learns which sizes the path carries
+fn on_mtu_probe(socket: &UdpSocket, src: SocketAddr, probe: MtuProbe, amt: usize) {
+	// Never answer with more than was received
+	if probe.echo || probe.size as usize != amt {
+		return;
+	}
+
+	if socket.send_to(&MtuProbe::padded_to(amt, true).encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
+
+// The confirmed session a request belongs to and the share it downloads, or why it is
+// refused. A session is only served to the address it logged in from, the share it logged
+// in to, and while the share is served. Session ids travel in the clear, so without the
+// address check anyone could have payloads sent at someone else.
+fn session_share(
+	host: &Host,
+	src: SocketAddr,
+	session_id: u64,
+	share_id: Option<u32>,
+) -> Result<(Arc<Session>, Arc<HostedShare>), String> {
+	let session = host.sessions.lock().unwrap().get(session_id);
+	let session = session
+		.filter(|(_, addr)| *addr == src)
+		.map(|(session, _)| session)
+		.filter(|session| share_id.is_none_or(|share_id| share_id == session.share_id))
+		.ok_or("it has no valid session")?;
+	let share = host.shares.read().unwrap().get(session.share_id).ok_or("its share is no longer shared")?;
+	share.info.availability.serves_sessions(unix_now()).map_err(|reason| reason.to_string())?;
+	Ok((session, share))
+}
+
+// Count a complete download against the share's limit, its announcement shows what is left.
+// It is signed without holding the catalog and only swapped in if the share did not change
+// meanwhile, otherwise the download is counted against the change.
+fn count_download(host: &Host, share_id: u32) {
+	let counted = reannounce_share(host, share_id, |mut availability| {
+		availability.downloads_left = availability.downloads_left.map(|left| left.saturating_sub(1));
+		availability
+	});
+	if let Some(left) = counted.and_then(|availability| availability.downloads_left) {
+		log_info(&format!("Share {} was downloaded, {} downloads left", share_id, left));
+	}
+}
 
-		if let Ok(serialized) = bincode::serialize(&types::ReditPacket::Payload(response_payload)) {
-			if let Err(_) = socket.send_to(&serialized, src) {
-				log_error("Couldn't send data");
+// Sign a share again with the availability `update` gives it. It is signed outside the catalog
+// lock and swapped in unless it changed meanwhile, then it starts over. None if it was removed.
+fn reannounce_share(host: &Host, share_id: u32, update: impl Fn(Availability) -> Availability) -> Option<Availability> {
+	loop {
+		let share = host.shares.read().unwrap().get(share_id)?;
+		let profile = host.profile.read().unwrap().clone();
+
+		let availability = update(share.info.availability);
+		let announced = profile.reannounce(&share, availability);
+		if host.shares.write().unwrap().replace(&share, announced) {
+			return Some(availability);
+		}
+	}
+}
+
+fn on_download_finished(host: &Host, src: SocketAddr, finished: DownloadFinished) {
+	match session_share(host, src, finished.session_id, Some(finished.share_id)) {
+		Ok((session, share)) if share.info.availability.downloads_left.is_some() => {
+			if session.record_finished() {
+				count_download(host, finished.share_id);
 			}
 		}
-		return;
+		Ok(_) => {}
+		Err(reason) => log_warning(&format!("Ignoring finished download from {}: {}", src, reason)),
 	}
+}
+
+// Tell a client that it has no valid session, so it stops asking
+fn refuse_session(socket: &UdpSocket, src: SocketAddr) {
+	let response = ReditPacket::Payload(Payload {
+		success: false,
+		index: 0,
+		payload_count: 0,
+		data: Vec::new(),
+	});
+	if socket.send_to(&response.encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
 
-	// Calculate the data range
-	let chunk = payload_index as u64;
-	let data_start = chunk * u64::from(PAYLOAD_SIZE);
-	let data_end = (chunk + 1) * u64::from(PAYLOAD_SIZE).min(file_size);
+// Paced payloads pass no share, theirs was checked when they were requested
+fn send_payload(host: &Host, src: SocketAddr, session_id: u64, share_id: Option<u32>, payload_index: u32) {
+	let socket = &host.socket;
 
-	// Read and encrypt the file chunk
-	let data = match read_file_chunk(&file_path, data_start.into(), data_end.into()) {
+	// Only clients that logged in are served
+	let (session, share) = match session_share(host, src, session_id, share_id) {
+		Ok(found) => found,
+		Err(reason) => {
+			log_error(&format!("Refusing payload request from {}: {}", src, reason));
+			refuse_session(socket, src);
+			return;
+		}
+	};
+	let file_size = share.content.size();
+
+	// Calculate the data range with the payload size of the session
+	let payload_size = u64::from(session.payload_size);
+	let payload_count = u32::try_from(file_size.div_ceil(payload_size)).unwrap_or(0);
+	if payload_index >= payload_count {
+		log_error(&format!("Refusing out of range payload {} from {}", payload_index, src));
+		return;
+	}
+	// Read and encrypt the file chunk, the last one is short
+	let data = match share.content.read(u64::from(payload_index) * payload_size, payload_size as usize) {
 		Ok(data) => data,
-		Err(_) => {
-			log_error("Failed to read file chunk");
+		Err(e) => {
+			log_error(&format!("Failed to read payload {}: {}", payload_index, e));
 			return;
 		}
 	};
 
-	let key = derive_key(password_ref);
-	let encrypted_data = encrypt_with_passphrase(&data, &key);
+	let encrypted_data = session.cipher.encrypt(&data, payload_index, payload_count);
+
+	// Repair symbols follow the last payload of every group
+	let repair_symbols = match session.repair.as_ref() {
+		Some(encoder) => encoder.lock().unwrap().on_sent(payload_index, payload_count, &encrypted_data),
+		None => Vec::new(),
+	};
 
 	// Create and send the response payload
 	let response_payload = Payload {
 		success: true,
 		index: payload_index,
-		payload_count: chunk_count.try_into().unwrap_or(0),
+		payload_count,
 		data: encrypted_data,
 	};
 
-	if let Ok(serialized) = bincode::serialize(&types::ReditPacket::Payload(response_payload)) {
-		if let Err(_) = socket.send_to(&serialized, src) {
+	let serialized = ReditPacket::Payload(response_payload).encode();
+	if socket.send_to(&serialized, src).is_err() {
+		log_error("Couldn't send data");
+	} else if share.info.availability.downloads_left.is_some() && session.record_sent(payload_index, payload_count) {
+		count_download(host, session.share_id);
+	}
+
+	for symbol in repair_symbols {
+		if socket.send_to(&ReditPacket::RepairSymbol(symbol).encode(), src).is_err() {
 			log_error("Couldn't send data");
 		}
 	}
 }
 
-pub fn start_listener(
-	uploader_info: UploaderInfo,
-	file_path: &Path,
-	password: Option<String>,
-	private_key: RsaPrivateKey,
-) {
-	let file_size: u64 = std::fs::metadata(file_path).unwrap().len();
-	let chunk_count = file_size.div_ceil(PAYLOAD_SIZE.into());
+// Queue the payloads of a range request, they are sent at the pace the client asked for
+fn on_request_payload_range(host: &Host, src: SocketAddr, pacer: &mut Pacer, request: RequestPayloadRange) {
+	let (session, share) = match session_share(host, src, request.session_id, Some(request.share_id)) {
+		Ok(found) => found,
+		Err(reason) => {
+			log_error(&format!("Refusing payload range request from {}: {}", src, reason));
+			refuse_session(&host.socket, src);
+			return;
+		}
+	};
+
+	let payload_count = u32::try_from(share.content.size().div_ceil(session.payload_size.into())).unwrap_or(u32::MAX);
+	let count = request.count.min(MAX_RANGE_LENGTH);
+	let end = request.start.saturating_add(count).min(payload_count);
+	let interval = Duration::from_micros(request.interval_micros.into()).min(MAX_PACING_INTERVAL);
 
+	pacer.add(src, request.session_id, request.start, end, interval, Instant::now());
+}
+
+// Send the hashes of the blocks the payloads of a session are checked against
+fn on_request_node_hashes(host: &Host, src: SocketAddr, request: RequestNodeHashes) {
+	let (session, share) = match session_share(host, src, request.session_id, Some(request.share_id)) {
+		Ok(found) => found,
+		Err(reason) => {
+			log_error(&format!("Refusing node hash request from {}: {}", src, reason));
+			return;
+		}
+	};
+
+	// No more hashes than a payload of the session has bytes, so the answer fits the path
+	let blocks = share.tree.leaves();
+	let start = (request.start as usize).min(blocks.len());
+	let count = request.count.min(session.payload_size / 32) as usize;
+	let response = ReditPacket::NodeHashes(NodeHashes {
+		start: request.start,
+		hashes: blocks[start..(start + count).min(blocks.len())].to_vec(),
+	});
+
+	if host.socket.send_to(&response.encode(), src).is_err() {
+		log_error("Couldn't send data");
+	}
+}
+
+// What the workers serve the requests of clients with
+struct Host {
+	socket: UdpSocket,
+	// Changes when the daemon is given a new passphrase
+	profile: RwLock<HostProfile>,
+	shares: RwLock<Shares>,
+	sessions: Mutex<Sessions>,
+}
+
+// Requests the workers handle, everything but range requests which only feed the pacer
+enum Job {
+	// Boxed, queued payloads are many and packets can be large
+	Packet(SocketAddr, Box<ReditPacket>),
+	Payload(DuePayload),
+}
+
+fn handle_job(host: &Host, job: Job) {
+	let (src, packet) = match job {
+		Job::Payload(due) => return send_payload(host, due.src, due.session_id, None, due.index),
+		Job::Packet(src, packet) => (src, *packet),
+	};
+
+	match packet {
+		ReditPacket::Hello(hello) => on_hello(&host.socket, src, hello),
+		ReditPacket::RequestCatalog(request) => on_request_catalog(host, src, request),
+		ReditPacket::RequestUploaderInfo(request) => on_request_uploader_info(host, src, request),
+		ReditPacket::RequestScanStore(_) => {
+			let profile = host.profile.read().unwrap();
+			scan::submit_scan_store(&host.socket, src, &profile.identity, Some(profile.public_key.clone()))
+		}
+		ReditPacket::LoginRequest(request) => on_login_request(host, src, request),
+		ReditPacket::LoginConfirm(confirm) => on_login_confirm(host, src, confirm),
+		ReditPacket::RequestPayload(request) => send_payload(
+			host,
+			src,
+			request.session_id,
+			Some(request.share_id),
+			request.payload_index,
+		),
+		ReditPacket::RequestNodeHashes(request) => on_request_node_hashes(host, src, request),
+		ReditPacket::DownloadFinished(finished) => on_download_finished(host, src, finished),
+		unexpected => log_error(&format!("Received unexpected packet {:?}", unexpected)),
+	}
+}
+
+// Packets are received and paced on one thread and handled by a pool of workers. Clients,
+// told apart by their address, are served in turns and by one worker at a time, so a slow
+// or busy client cannot hold up the others.
+pub fn start_listener(profile: HostProfile, shares: Shares, control: Option<ControlSocket>) {
 	let socket = UdpSocket::bind("0.0.0.0:6969").unwrap();
-	log_info("Hosting...");
+	// Probe echoes have to arrive whole or not at all
+	if let Err(e) = forbid_fragmentation(&socket) {
+		log_warning(&format!("Failed to forbid fragmentation, path MTU probes may overestimate: {}", e));
+	}
+	let receiver = match socket.try_clone() {
+		Ok(receiver) => receiver,
+		Err(e) => {
+			log_error(&format!("Failed to share the socket with the workers: {}", e));
+			return;
+		}
+	};
 
-	let mut buf = [0; 1024];
+	let host = Arc::new(Host {
+		socket,
+		profile: RwLock::new(profile),
+		shares: RwLock::new(shares),
+		sessions: Mutex::new(Sessions::default()),
+	});
+	if let Some(control) = control {
+		let control_host = host.clone();
+		thread::spawn(move || control.serve(|request| on_control_request(&control_host, request)));
+	}
+	let workers = thread::available_parallelism().map_or(MIN_WORKERS, usize::from).max(MIN_WORKERS);
+	let worker_host = host.clone();
+	let pool = WorkerPool::new(workers, MAX_QUEUED_JOBS, MAX_QUEUED_JOBS_TOTAL, move |job| handle_job(&worker_host, job));
+	log_info(&format!("Hosting with {} workers...", workers));
 
-	let mut salt_mappings: std::collections::HashMap<SocketAddr, String> = Default::default();
+	let mut buf = vec![0; 65536];
+	let mut pacer = Pacer::default();
 
 	// Listen for incoming packets
 
 	loop {
-		let (amt, src) = socket.recv_from(&mut buf).unwrap();
+		// Hand out the payloads that are due, then wait for packets until the next one is
+		let now = Instant::now();
+		// Payloads of clients with a full queue are dropped, the clients request them again
+		while let Some(due) = pacer.pop_due(now) {
+			pool.submit(due.src.ip(), Job::Payload(due));
+		}
+
+		let timeout = pacer
+			.next_deadline()
+			.map(|deadline| deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+		if receiver.set_read_timeout(timeout).is_err() {
+			log_error("Failed to set the socket timeout");
+		}
+
+		let (amt, src) = match receiver.recv_from(&mut buf) {
+			Ok(received) => received,
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+			Err(e) => {
+				log_error(&format!("Failed to receive packet: {}", e));
+				continue;
+			}
+		};
 
 		let packet_data = &buf[..amt];
-		let packet: types::ReditPacket = match bincode::deserialize(packet_data) {
+		let packet = match ReditPacket::decode(packet_data) {
 			Ok(data) => data,
 			Err(e) => {
-				log_error(&format!("Received undeserializable packet: {:?}", e));
+				on_undecodable_packet(&receiver, src, e);
 				continue;
 			}
 		};
 
+		// Range requests and probes are cheap, and probes are large, so neither is queued
 		match packet {
-			types::ReditPacket::RequestUploaderInfo(_) => on_request_uploader_info(
-				socket.try_clone().unwrap(),
-				src,
-				uploader_info.clone(),
-				&mut salt_mappings,
-				file_size,
-			),
-			types::ReditPacket::RequestScanStore(_) => scan::submit_scan_store(&socket, src),
-			types::ReditPacket::RequestPayload(res) => on_request_payload(
-				socket.try_clone().unwrap(),
-				src,
-				private_key.clone(),
-				res.hashed_password,
-				password.clone(),
-				chunk_count,
-				res.payload_index,
-				file_size,
-				file_path,
-			),
-			unexpected => log_error(&format!("Received unexpected packet {:?}", unexpected)),
+			ReditPacket::RequestPayloadRange(request) => on_request_payload_range(&host, src, &mut pacer, request),
+			ReditPacket::MtuProbe(probe) => on_mtu_probe(&receiver, src, probe, amt),
+			packet => {
+				if !pool.submit(src.ip(), Job::Packet(src, Box::new(packet))) {
+					log_warning(&format!("Dropping a request from {}, it has too many queued", src));
+				}
+			}
 		}
 	}
 }
-
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::ClientStatus;
+use crate::encryption::{confirmation_matches, PayloadCipher};
+use crate::fec::RepairEncoder;
+use crate::types::{FecParams, AUTHENTICATION_TAG_SIZE};
+
+// Logins that were never confirmed are forgotten quickly, idle sessions eventually
+const UNCONFIRMED_TIMEOUT: Duration = Duration::from_secs(30);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// What the payloads of a session are served with. Workers hold on to it while they
+// encrypt, so the sessions are not locked meanwhile.
+pub struct Session {
+	pub share_id: u32,
+	pub cipher: PayloadCipher,
+	pub payload_size: u32,
+	// Codes repair symbols for sessions that asked for forward error correction
+	pub repair: Option<Mutex<RepairEncoder>>,
+	progress: Mutex<Progress>,
+}
+
+// The payloads a session was sent. Its download counts once, when it was sent every payload
+// or the client said it finished, whichever comes first.
+#[derive(Default)]
+struct Progress {
+	sent: Vec<bool>,
+	left: usize,
+	counted: bool,
+}
+
+impl Session {
+	// Note a payload that was sent, true if that completed the download
+	pub fn record_sent(&self, index: u32, payload_count: u32) -> bool {
+		let mut progress = self.progress.lock().unwrap();
+		if progress.sent.is_empty() {
+			progress.sent = vec![false; payload_count as usize];
+			progress.left = payload_count as usize;
+		}
+
+		match progress.sent.get_mut(index as usize) {
+			Some(sent) if !*sent => *sent = true,
+			_ => return false,
+		}
+		progress.left -= 1;
+		progress.left == 0 && !std::mem::replace(&mut progress.counted, true)
+	}
+
+	// The client finished the download, true if it was not counted yet
+	pub fn record_finished(&self) -> bool {
+		!std::mem::replace(&mut self.progress.lock().unwrap().counted, true)
+	}
+}
+
+struct Entry {
+	session: Arc<Session>,
+	// Where the client logged in from
+	addr: SocketAddr,
+	expected_confirmation: [u8; 32],
+	confirmed: bool,
+	last_seen: Instant,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+	sessions: HashMap<u64, Entry>,
+}
+
+impl Sessions {
+	// Register a login that still has to be confirmed by the client, returns the session id
+	pub fn insert(
+		&mut self,
+		share_id: u32,
+		addr: SocketAddr,
+		cipher: PayloadCipher,
+		payload_size: u32,
+		fec: Option<FecParams>,
+		expected_confirmation: [u8; 32],
+	) -> u64 {
+		self.expire();
+
+		let mut session_id = rand::random::<u64>();
+		while self.sessions.contains_key(&session_id) {
+			session_id = rand::random::<u64>();
+		}
+
+		let repair = fec.map(|params| RepairEncoder::new(params, payload_size as usize + AUTHENTICATION_TAG_SIZE));
+		self.sessions.insert(
+			session_id,
+			Entry {
+				session: Arc::new(Session {
+					share_id,
+					cipher,
+					payload_size,
+					repair: repair.map(Mutex::new),
+					progress: Mutex::default(),
+				}),
+				addr,
+				expected_confirmation,
+				confirmed: false,
+				last_seen: Instant::now(),
+			},
+		);
+		session_id
+	}
+
+	// Confirm a login, a failed confirmation ends a pending login
+	pub fn confirm(&mut self, session_id: u64, confirmation: [u8; 32]) -> bool {
+		let entry = match self.sessions.get_mut(&session_id) {
+			Some(entry) => entry,
+			None => return false,
+		};
+
+		if !confirmation_matches(entry.expected_confirmation, confirmation) {
+			if !entry.confirmed {
+				self.sessions.remove(&session_id);
+			}
+			return false;
+		}
+
+		entry.confirmed = true;
+		entry.last_seen = Instant::now();
+		true
+	}
+
+	// Look up a confirmed session and the address it logged in from
+	pub fn get(&mut self, session_id: u64) -> Option<(Arc<Session>, SocketAddr)> {
+		let entry = self.sessions.get_mut(&session_id)?;
+		if !entry.confirmed {
+			return None;
+		}
+
+		entry.last_seen = Instant::now();
+		Some((entry.session.clone(), entry.addr))
+	}
+
+	// End the sessions of a share that is no longer shared
+	pub fn end_share(&mut self, share_id: u32) {
+		self.sessions.retain(|_, entry| entry.session.share_id != share_id);
+	}
+
+	// The clients that are logged in or logging in
+	pub fn clients(&mut self) -> Vec<ClientStatus> {
+		self.expire();
+		self.sessions
+			.values()
+			.map(|entry| ClientStatus {
+				addr: entry.addr,
+				share_id: entry.session.share_id,
+				confirmed: entry.confirmed,
+				idle_secs: entry.last_seen.elapsed().as_secs(),
+			})
+			.collect()
+	}
+
+	fn expire(&mut self) {
+		self.sessions.retain(|_, entry| {
+			let timeout = if entry.confirmed {
+				IDLE_TIMEOUT
+			} else {
+				UNCONFIRMED_TIMEOUT
+			};
+			entry.last_seen.elapsed() < timeout
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn client() -> SocketAddr {
+		"10.0.0.2:6969".parse().unwrap()
+	}
+
+	#[test]
+	fn test_confirmation() {
+		let mut sessions = Sessions::default();
+		let session_id = sessions.insert(0, client(), PayloadCipher::new(&[1u8; 32], [0u8; 8]), 1024, None, [2u8; 32]);
+
+		assert!(sessions.get(session_id).is_none(), "Unconfirmed sessions must not be usable");
+		assert!(sessions.confirm(session_id, [2u8; 32]));
+
+		// The session serves payloads with the cipher of its login
+		let (session, addr) = sessions.get(session_id).unwrap();
+		assert_eq!(addr, client(), "The session is bound to where it logged in from");
+		let encrypted_data = session.cipher.encrypt(b"payload", 0, 1);
+		let decrypted_data = PayloadCipher::new(&[1u8; 32], [0u8; 8]).decrypt(&encrypted_data, 0, 1);
+		assert_eq!(decrypted_data.unwrap(), b"payload");
+
+		// Confirming twice is harmless, the confirmation may have been retransmitted
+		assert!(sessions.confirm(session_id, [2u8; 32]));
+	}
+
+	#[test]
+	fn test_failed_confirmation() {
+		let mut sessions = Sessions::default();
+		let session_id = sessions.insert(0, client(), PayloadCipher::new(&[1u8; 32], [0u8; 8]), 1024, None, [2u8; 32]);
+
+		assert!(!sessions.confirm(session_id, [3u8; 32]));
+		assert!(!sessions.confirm(session_id, [2u8; 32]), "A failed login must end the session");
+		assert!(sessions.get(session_id).is_none());
+	}
+
+	#[test]
+	fn test_removed_shares_end_their_sessions() {
+		let mut sessions = Sessions::default();
+		let kept = sessions.insert(0, client(), PayloadCipher::new(&[1u8; 32], [0u8; 8]), 1024, None, [2u8; 32]);
+		let ended = sessions.insert(1, client(), PayloadCipher::new(&[1u8; 32], [0u8; 8]), 1024, None, [2u8; 32]);
+		assert!(sessions.confirm(kept, [2u8; 32]));
+		assert!(sessions.confirm(ended, [2u8; 32]));
+
+		sessions.end_share(1);
+		assert!(sessions.get(ended).is_none());
+		assert!(sessions.get(kept).is_some());
+
+		let clients = sessions.clients();
+		assert_eq!(clients.len(), 1);
+		assert_eq!(clients[0].addr, client());
+		assert_eq!(clients[0].share_id, 0);
+		assert!(clients[0].confirmed);
+	}
+
+	#[test]
+	fn test_downloads_count_once() {
+		let mut sessions = Sessions::default();
+		let session_id = sessions.insert(0, client(), PayloadCipher::new(&[1u8; 32], [0u8; 8]), 1024, None, [2u8; 32]);
+		assert!(sessions.confirm(session_id, [2u8; 32]));
+		let (session, _) = sessions.get(session_id).unwrap();
+
+		assert!(!session.record_sent(0, 3));
+		assert!(!session.record_sent(2, 3));
+		assert!(!session.record_sent(2, 3), "Payloads sent again do not count");
+		assert!(session.record_sent(1, 3));
+		assert!(!session.record_finished(), "The download was already counted");
+		assert!(!session.record_sent(1, 3));
+
+		let other = sessions.insert(0, client(), PayloadCipher::new(&[1u8; 32], [0u8; 8]), 1024, None, [2u8; 32]);
+		assert!(sessions.confirm(other, [2u8; 32]));
+		let (other, _) = sessions.get(other).unwrap();
+		assert!(other.record_finished());
+		assert!(!other.record_sent(0, 1));
+	}
+}
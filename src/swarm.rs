@@ -0,0 +1,233 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::retransmit::{ReceivedBitmap, RetriesExhausted, RetryPolicy, Retransmitter};
+
+// Splits the payloads of a download across the hosts it is fetched from. Every host
+// starts on its own share of the index space. A host that runs out takes over half of
+// what the host with the most left has not requested yet, so slow hosts end up with
+// less, and the payloads of a host that vanished are handed to the others.
+pub struct Swarm {
+	// Tracks every payload once, and what every host has outstanding
+	retransmitter: Retransmitter,
+	// Payloads no host was assigned after the one they belonged to was dropped
+	unclaimed: Vec<Range<u32>>,
+}
+
+impl Swarm {
+	pub fn new(payload_count: u32, hosts: usize, policy: RetryPolicy, received: &ReceivedBitmap) -> Self {
+		let mut retransmitter = Retransmitter::new(payload_count, hosts, policy);
+		for index in (0..payload_count).filter(|index| received.contains(*index)) {
+			retransmitter.on_received(index);
+		}
+
+		let share = payload_count.div_ceil(hosts.max(1) as u32);
+		for lane in 0..hosts {
+			let start = (lane as u32).saturating_mul(share).min(payload_count);
+			retransmitter.assign(lane, start..start.saturating_add(share));
+		}
+
+		Swarm {
+			retransmitter,
+			unclaimed: Vec::new(),
+		}
+	}
+
+	pub fn is_active(&self, lane: usize) -> bool {
+		self.retransmitter.is_active(lane)
+	}
+
+	// Record an arrived payload, whichever host it came from
+	pub fn on_received(&mut self, index: u32) {
+		self.retransmitter.on_received(index);
+	}
+
+	// Fetch payloads again that arrived but turned out to be corrupt, spread over the hosts
+	pub fn on_corrupt(&mut self, payloads: Range<u32>) {
+		let heirs = self.retransmitter.active_lanes();
+		if heirs.is_empty() {
+			return;
+		}
+		for (position, index) in payloads.enumerate() {
+			self.retransmitter.forget(index);
+			self.retransmitter.requeue(heirs[position % heirs.len()], index);
+		}
+	}
+
+	// Requests of a host that are still outstanding
+	pub fn in_flight(&self, lane: usize) -> usize {
+		self.retransmitter.in_flight(lane)
+	}
+
+	pub fn rtt_sample(&self, index: u32, now: Instant) -> Option<Duration> {
+		self.retransmitter.rtt_sample(index, now)
+	}
+
+	pub fn expire(&mut self, lane: usize, now: Instant) -> u32 {
+		self.retransmitter.expire(lane, now)
+	}
+
+	// Payloads to request from a host now, taking work over from others once it ran out
+	pub fn next_requests(
+		&mut self,
+		lane: usize,
+		now: Instant,
+		window: usize,
+		timeout: Duration,
+	) -> Result<Vec<u32>, RetriesExhausted> {
+		if !self.is_active(lane) {
+			return Ok(Vec::new());
+		}
+		let mut requests = self.retransmitter.next_requests(lane, now, window, timeout)?;
+		if self.retransmitter.in_flight(lane) >= window || !self.retransmitter.unrequested(lane).is_empty() {
+			return Ok(requests);
+		}
+
+		if let Some(range) = self.take_work(lane) {
+			self.retransmitter.assign(lane, range);
+			requests.extend(self.retransmitter.next_requests(lane, now, window, timeout)?);
+		}
+		Ok(requests)
+	}
+
+	// Unclaimed payloads first, otherwise half of what the host with the most left did not request
+	fn take_work(&mut self, lane: usize) -> Option<Range<u32>> {
+		if let Some(range) = self.unclaimed.pop() {
+			return Some(range);
+		}
+
+		let victim = self
+			.retransmitter
+			.active_lanes()
+			.into_iter()
+			.filter(|other| *other != lane)
+			.map(|other| (self.retransmitter.unrequested(other).len(), other))
+			.filter(|(left, _)| *left > 0)
+			.max_by_key(|(left, _)| *left)?
+			.1;
+		Some(self.retransmitter.split_unrequested(victim))
+	}
+
+	// Stop fetching from a host, its payloads go to the others. Returns false if no host is left.
+	pub fn drop_lane(&mut self, lane: usize) -> bool {
+		if !self.is_active(lane) {
+			return !self.retransmitter.active_lanes().is_empty();
+		}
+		let (pending, unrequested) = self.retransmitter.abandon(lane);
+
+		let heirs = self.retransmitter.active_lanes();
+		if heirs.is_empty() {
+			return false;
+		}
+		for (position, index) in pending.into_iter().enumerate() {
+			self.retransmitter.requeue(heirs[position % heirs.len()], index);
+		}
+		if !unrequested.is_empty() {
+			self.unclaimed.push(unrequested);
+		}
+		true
+	}
+
+	// The earliest moment an outstanding request of any host is considered lost
+	pub fn next_deadline(&self) -> Option<Instant> {
+		self.retransmitter.next_deadline()
+	}
+
+	pub fn retransmissions(&self) -> u64 {
+		self.retransmitter.retransmissions()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TIMEOUT: Duration = Duration::from_millis(100);
+
+	fn policy() -> RetryPolicy {
+		RetryPolicy {
+			max_attempts: 2,
+			initial_timeout: TIMEOUT,
+			max_timeout: Duration::from_millis(200),
+		}
+	}
+
+	#[test]
+	fn test_hosts_share_the_index_space() {
+		let mut received = ReceivedBitmap::new(12);
+		received.insert(1);
+		let mut swarm = Swarm::new(12, 3, policy(), &received);
+		let now = Instant::now();
+
+		assert_eq!(swarm.next_requests(0, now, 3, TIMEOUT), Ok(vec![0, 2, 3]));
+		assert_eq!(swarm.next_requests(1, now, 2, TIMEOUT), Ok(vec![4, 5]));
+		assert_eq!(swarm.next_requests(2, now, 1, TIMEOUT), Ok(vec![8]));
+	}
+
+	#[test]
+	fn test_fast_hosts_take_work_over() {
+		let mut swarm = Swarm::new(40, 2, policy(), &ReceivedBitmap::new(40));
+		let now = Instant::now();
+
+		// The second host is slow and only asked for two payloads of its half
+		assert_eq!(swarm.next_requests(1, now, 2, TIMEOUT), Ok(vec![20, 21]));
+		assert_eq!(swarm.next_requests(0, now, 20, TIMEOUT), Ok((0..20).collect()));
+		for index in 0..20 {
+			swarm.on_received(index);
+		}
+
+		// The first host runs out and takes over the upper half of what the second did not ask for
+		assert_eq!(swarm.next_requests(0, now, 3, TIMEOUT), Ok(vec![31, 32, 33]));
+		assert_eq!(
+			swarm.next_requests(1, now, 20, TIMEOUT),
+			Ok((22..31).chain(37..40).collect())
+		);
+	}
+
+	#[test]
+	fn test_vanished_hosts_are_dropped() {
+		let mut swarm = Swarm::new(20, 2, policy(), &ReceivedBitmap::new(20));
+		let start = Instant::now();
+
+		assert_eq!(swarm.next_requests(0, start, 5, TIMEOUT), Ok(vec![0, 1, 2, 3, 4]));
+		assert_eq!(swarm.next_requests(1, start, 5, TIMEOUT), Ok(vec![10, 11, 12, 13, 14]));
+		for index in 0..5 {
+			swarm.on_received(index);
+		}
+		swarm.on_received(10);
+
+		// The second host never answers again and runs out of retries
+		let later = start + Duration::from_millis(150);
+		assert_eq!(swarm.next_requests(1, later, 5, TIMEOUT), Ok(vec![11, 12, 13, 14, 15]));
+		let much_later = later + Duration::from_secs(1);
+		assert_eq!(
+			swarm.next_requests(1, much_later, 5, TIMEOUT),
+			Err(RetriesExhausted { index: 11, attempts: 2 })
+		);
+		assert!(swarm.drop_lane(1));
+		assert!(!swarm.is_active(1));
+
+		// Its lost payloads go first, then the ones it never asked for once the first host ran out
+		assert_eq!(
+			swarm.next_requests(0, much_later, 20, TIMEOUT),
+			Ok((11..16).chain(5..10).chain(16..20).collect())
+		);
+		assert_eq!(swarm.retransmissions(), 5 + 5);
+
+		assert!(!swarm.drop_lane(0), "No host is left");
+	}
+
+	#[test]
+	fn test_corrupt_payloads_are_fetched_again() {
+		let mut swarm = Swarm::new(8, 2, policy(), &ReceivedBitmap::new(8));
+		let now = Instant::now();
+
+		assert_eq!(swarm.next_requests(0, now, 4, TIMEOUT), Ok(vec![0, 1, 2, 3]));
+		for index in 0..4 {
+			swarm.on_received(index);
+		}
+		swarm.on_corrupt(1..3);
+		assert_eq!(swarm.next_requests(0, now, 1, TIMEOUT), Ok(vec![1]));
+		assert_eq!(swarm.next_requests(1, now, 1, TIMEOUT), Ok(vec![2]));
+	}
+}
@@ -0,0 +1,56 @@
+// Helpers the tests of several modules share
+use std::fs;
+use std::path::PathBuf;
+
+use crate::policy::Availability;
+use crate::types::{KdfParams, PackagingType, UploaderInfo};
+
+// A directory of its own in the temporary directory, removed with everything in it when
+// dropped, even if the test panicked
+pub struct TempDir {
+	path: PathBuf,
+}
+
+impl TempDir {
+	pub fn new(prefix: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("redit-{}-{}", prefix, rand::random::<u64>()));
+		fs::create_dir_all(&path).unwrap();
+		TempDir { path }
+	}
+
+	pub fn join(&self, name: &str) -> PathBuf {
+		self.path.join(name)
+	}
+
+	// A file of the directory holding `data`
+	pub fn file(&self, name: &str, data: &[u8]) -> PathBuf {
+		let path = self.join(name);
+		fs::write(&path, data).unwrap();
+		path
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.path);
+	}
+}
+
+// An unsigned announcement of a private share
+pub fn announcement() -> UploaderInfo {
+	UploaderInfo {
+		public: false,
+		name: "builds".to_string(),
+		share_id: 0,
+		files_size: 1024,
+		file_name: "artifact.zip".to_string(),
+		packaging: PackagingType::None,
+		public_key: None,
+		hashed_connection_salt: None,
+		kdf_params: KdfParams::default(),
+		nonce_seed: [0u8; 8],
+		merkle_root: [0u8; 32],
+		availability: Availability::default(),
+		signature: Vec::new(),
+	}
+}
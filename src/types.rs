@@ -1,13 +1,71 @@
+use crate::policy::{Availability, Unavailable};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fmt;
 use std::net::IpAddr;
 
+// Largest payload a session can negotiate, paths with a smaller MTU get smaller ones
 pub const PAYLOAD_SIZE: u32 = 32768;
 pub const PORT: u16 = 6969;
 
+// Every datagram starts with the magic bytes followed by the protocol version
+// (little endian), so peers running another build can be told apart from noise
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"RDIT";
+// Bumped whenever the wire format changes, builds from before the header have none
+pub const PROTOCOL_VERSION: u16 = 2;
+const HEADER_SIZE: usize = PROTOCOL_MAGIC.len() + 2;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct RequestUploaderInfo {
 	pub public_key: Option<String>,
+	// The share of the host's catalog to describe
+	pub share_id: u32,
+}
+
+// Asks a host for its catalog, from share `start` on
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RequestCatalog {
+	pub start: u32,
+}
+
+// A share of a host's catalog, its UploaderInfo has everything needed to download it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CatalogEntry {
+	pub share_id: u32,
+	pub name: String,
+	pub files_size: u64,
+	pub packaging: PackagingType,
+	pub availability: Availability,
+}
+
+// The shares of a host, a page at a time. `next` is the share to ask for the next page from.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Catalog {
+	pub shares: Vec<CatalogEntry>,
+	pub next: Option<u32>,
+}
+
+impl Catalog {
+	// As many of `entries` as fit a packet of `max_size` bytes, pointing at the rest
+	pub fn page(entries: impl IntoIterator<Item = CatalogEntry>, max_size: usize) -> Catalog {
+		let mut catalog = Catalog {
+			shares: Vec::new(),
+			next: None,
+		};
+		// Leave room for `next`, which is still empty
+		let mut size = ReditPacket::Catalog(catalog.clone()).encode().len() + 4;
+
+		for entry in entries {
+			let entry_size = bincode::serialized_size(&entry).expect("Failed to size catalog entry") as usize;
+			if size + entry_size > max_size && !catalog.shares.is_empty() {
+				catalog.next = Some(entry.share_id);
+				break;
+			}
+			size += entry_size;
+			catalog.shares.push(entry);
+		}
+		catalog
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash)]
@@ -16,23 +74,109 @@ pub enum PackagingType {
 	Tarred,
 }
 
+// Argon2id parameters a share's key is derived with
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Eq, Hash)]
+pub struct KdfParams {
+	pub memory_kib: u32,
+	pub iterations: u32,
+	pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+	fn default() -> Self {
+		KdfParams {
+			memory_kib: 65536,
+			iterations: 4,
+			parallelism: 1,
+		}
+	}
+}
+
+impl KdfParams {
+	// The parameters are chosen by the host, refuse ones that would exhaust the client
+	pub fn is_acceptable(&self) -> bool {
+		(8 * 1024..=1024 * 1024).contains(&self.memory_kib)
+			&& (1..=16).contains(&self.iterations)
+			&& (1..=16).contains(&self.parallelism)
+	}
+}
+
+// Reed-Solomon forward error correction of a session: after every `group_size` payloads
+// the host sends `repair_symbols` symbols that each stand in for any one lost payload
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Eq, Hash)]
+pub struct FecParams {
+	pub group_size: u8,
+	pub repair_symbols: u8,
+}
+
+impl Default for FecParams {
+	fn default() -> Self {
+		FecParams {
+			group_size: 16,
+			repair_symbols: 0,
+		}
+	}
+}
+
+impl FecParams {
+	// Both peers keep whole groups in memory, refuse groups that would be too costly to code
+	pub fn is_acceptable(&self) -> bool {
+		(2..=64).contains(&self.group_size) && (1..=16).contains(&self.repair_symbols)
+	}
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash)]
 pub struct UploaderInfo {
 	pub public: bool,
 	pub name: String,
+	// Which share of the host's catalog this is
+	pub share_id: u32,
 	pub files_size: u64,
 	pub file_name: String,
 	pub packaging: PackagingType,
 	pub public_key: Option<String>,
+	// Random salt of the share's key derivation, base64 encoded
 	pub hashed_connection_salt: Option<String>,
+	pub kdf_params: KdfParams,
+	pub nonce_seed: [u8; 8],
+	// Root of the BLAKE3 Merkle tree over the shared file, which identifies the content.
+	// Every payload and the whole download are checked against it.
+	pub merkle_root: [u8; 32],
+	// When and how often the host serves the share
+	pub availability: Availability,
+	// Signature of the host's identity key over all other fields
+	pub signature: Vec<u8>,
+}
+
+impl UploaderInfo {
+	// The bytes covered by the signature
+	pub fn signed_bytes(&self) -> Vec<u8> {
+		let mut unsigned = self.clone();
+		unsigned.signature.clear();
+		bincode::serialize(&unsigned).expect("Failed to serialize uploader info")
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct RequestPayload {
-	pub hashed_password: Vec<u8>,
+	pub session_id: u64,
+	pub share_id: u32,
 	pub payload_index: u32,
 }
 
+// Most payloads a range request is served, hosts cut longer ones short
+pub const MAX_RANGE_LENGTH: u32 = 1024;
+
+// Asks for `count` payloads from `start` on, streamed back one every `interval_micros`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RequestPayloadRange {
+	pub session_id: u64,
+	pub share_id: u32,
+	pub start: u32,
+	pub count: u32,
+	pub interval_micros: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Payload {
 	pub success: bool,
@@ -41,6 +185,74 @@ pub struct Payload {
 	pub data: Vec<u8>,
 }
 
+// Size of the authentication tag every encrypted payload carries
+pub const AUTHENTICATION_TAG_SIZE: usize = 16;
+
+// Bytes a Payload packet adds to the data it carries
+pub fn payload_overhead() -> usize {
+	let empty = ReditPacket::Payload(Payload {
+		success: true,
+		index: 0,
+		payload_count: 0,
+		data: Vec::new(),
+	});
+	empty.encode().len() + AUTHENTICATION_TAG_SIZE
+}
+
+// Tests whether datagrams of `size` bytes make it to the host, which echoes them back as large
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MtuProbe {
+	pub size: u32,
+	pub echo: bool,
+	pub padding: Vec<u8>,
+}
+
+impl MtuProbe {
+	// A probe whose datagram is exactly `size` bytes
+	pub fn padded_to(size: usize, echo: bool) -> ReditPacket {
+		let probe = |padding| {
+			ReditPacket::MtuProbe(MtuProbe {
+				size: size as u32,
+				echo,
+				padding,
+			})
+		};
+		let unpadded = probe(Vec::new()).encode().len();
+		probe(vec![0; size.saturating_sub(unpadded)])
+	}
+}
+
+// Repair symbol `symbol` of the payloads in group `group`, coded over their encrypted data
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RepairSymbol {
+	pub group: u32,
+	pub symbol: u8,
+	pub data: Vec<u8>,
+}
+
+// Asks for the hashes of blocks `start` to `start + count` of the share a session downloads
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RequestNodeHashes {
+	pub session_id: u64,
+	pub share_id: u32,
+	pub start: u32,
+	pub count: u32,
+}
+
+// Tells a host that the session's download completed, so it counts against the download limit
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DownloadFinished {
+	pub session_id: u64,
+	pub share_id: u32,
+}
+
+// Hashes of the blocks from `start` on, the leaves of the share's Merkle tree
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct NodeHashes {
+	pub start: u32,
+	pub hashes: Vec<[u8; 32]>,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ClientConnectionInfo {
 	pub encrypted_password: Vec<u8>,
@@ -57,6 +269,106 @@ pub struct RequestScanStore {}
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ScanStore {
 	pub store: HashSet<IpAddr>,
+	pub public_key: Option<String>,
+	// Signature of the host's identity key over the store and public key
+	pub signature: Vec<u8>,
+}
+
+impl ScanStore {
+	// The bytes covered by the signature, the store is sorted since set order differs between processes
+	pub fn signed_bytes(&self) -> Vec<u8> {
+		let mut store: Vec<&IpAddr> = self.store.iter().collect();
+		store.sort();
+		bincode::serialize(&(store, &self.public_key)).expect("Failed to serialize scan store")
+	}
+}
+
+// Client's SPAKE2 message and ephemeral X25519 key, starts a login
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LoginRequest {
+	pub spake_message: Vec<u8>,
+	pub ephemeral_public: [u8; 32],
+	// The share the session downloads, its payloads are the only ones it is served
+	pub share_id: u32,
+	// Largest payload that fits the client's path to the host
+	pub payload_size: u32,
+	// Forward error correction the client would like, if any
+	pub fec: Option<FecParams>,
+}
+
+// Server's SPAKE2 message, ephemeral X25519 key and proof that it derived the session key
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LoginChallenge {
+	pub session_id: u64,
+	pub spake_message: Vec<u8>,
+	pub ephemeral_public: [u8; 32],
+	pub confirmation: [u8; 32],
+	// Payload size of the session, at most the one the client asked for
+	pub payload_size: u32,
+	// Forward error correction the host agreed to
+	pub fec: Option<FecParams>,
+	// Signature of the host's identity key over the challenge and the request it answers
+	pub signature: Vec<u8>,
+}
+
+impl LoginChallenge {
+	// The bytes covered by the signature
+	pub fn signed_bytes(&self, request: &LoginRequest) -> Vec<u8> {
+		bincode::serialize(&(
+			request,
+			self.session_id,
+			&self.spake_message,
+			self.ephemeral_public,
+			self.confirmation,
+			self.payload_size,
+			self.fec,
+		))
+		.expect("Failed to serialize login challenge")
+	}
+}
+
+// Client's proof that it derived the same session key
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LoginConfirm {
+	pub session_id: u64,
+	pub confirmation: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LoginResult {
+	pub session_id: u64,
+	pub success: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Eq, Hash)]
+pub enum Cipher {
+	Aes256Gcm,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Capabilities {
+	pub protocol_version: u16,
+	pub ciphers: Vec<Cipher>,
+	pub packaging: Vec<PackagingType>,
+	pub max_payload_size: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Hello {
+	pub capabilities: Capabilities,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum RejectReason {
+	VersionMismatch { expected: u16, received: u16 },
+	NoCommonCipher,
+	NoCommonPackaging,
+	Unavailable(Unavailable),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Reject {
+	pub reason: RejectReason,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -70,5 +382,247 @@ pub enum ReditPacket {
 	ServerConnectionInfo(ServerConnectionInfo) = 5,
 	RequestScanStore(RequestScanStore) = 6,
 	ScanStore(ScanStore) = 7,
+	Hello(Hello) = 8,
+	Capabilities(Capabilities) = 9,
+	Reject(Reject) = 10,
+	LoginRequest(LoginRequest) = 11,
+	LoginChallenge(LoginChallenge) = 12,
+	LoginConfirm(LoginConfirm) = 13,
+	LoginResult(LoginResult) = 14,
+	RequestPayloadRange(RequestPayloadRange) = 15,
+	MtuProbe(MtuProbe) = 16,
+	RepairSymbol(RepairSymbol) = 17,
+	RequestNodeHashes(RequestNodeHashes) = 18,
+	NodeHashes(NodeHashes) = 19,
+	RequestCatalog(RequestCatalog) = 20,
+	Catalog(Catalog) = 21,
+	DownloadFinished(DownloadFinished) = 22,
+}
+
+impl fmt::Display for RejectReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			RejectReason::VersionMismatch { expected, received } => write!(
+				f,
+				"protocol version mismatch (expected {}, received {})",
+				expected, received
+			),
+			RejectReason::NoCommonCipher => write!(f, "no cipher supported by both peers"),
+			RejectReason::NoCommonPackaging => write!(f, "no packaging type supported by both peers"),
+			RejectReason::Unavailable(reason) => write!(f, "{}", reason),
+		}
+	}
+}
+
+impl Capabilities {
+	// The capabilities of this build
+	pub fn local() -> Self {
+		Capabilities {
+			protocol_version: PROTOCOL_VERSION,
+			ciphers: vec![Cipher::Aes256Gcm],
+			packaging: vec![PackagingType::None, PackagingType::Tarred],
+			max_payload_size: PAYLOAD_SIZE,
+		}
+	}
+
+	// Intersect our capabilities with the ones advertised by a peer
+	pub fn negotiate(&self, remote: &Capabilities) -> Result<Capabilities, RejectReason> {
+		if self.protocol_version != remote.protocol_version {
+			return Err(RejectReason::VersionMismatch {
+				expected: self.protocol_version,
+				received: remote.protocol_version,
+			});
+		}
+
+		let ciphers: Vec<Cipher> = self
+			.ciphers
+			.iter()
+			.filter(|cipher| remote.ciphers.contains(cipher))
+			.copied()
+			.collect();
+		if ciphers.is_empty() {
+			return Err(RejectReason::NoCommonCipher);
+		}
+
+		let packaging: Vec<PackagingType> = self
+			.packaging
+			.iter()
+			.filter(|packaging| remote.packaging.contains(packaging))
+			.cloned()
+			.collect();
+		if packaging.is_empty() {
+			return Err(RejectReason::NoCommonPackaging);
+		}
+
+		Ok(Capabilities {
+			protocol_version: self.protocol_version,
+			ciphers,
+			packaging,
+			max_payload_size: self.max_payload_size.min(remote.max_payload_size),
+		})
+	}
+}
+
+#[derive(Debug)]
+pub enum PacketError {
+	BadMagic,
+	VersionMismatch { expected: u16, received: u16 },
+	Malformed(bincode::Error),
+}
+
+impl fmt::Display for PacketError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PacketError::BadMagic => write!(f, "not a Redit packet"),
+			PacketError::VersionMismatch { expected, received } => write!(
+				f,
+				"peer speaks protocol version {}, expected {}",
+				received, expected
+			),
+			PacketError::Malformed(e) => write!(f, "malformed packet: {}", e),
+		}
+	}
+}
+
+impl ReditPacket {
+	// Serialize the packet behind the magic and version header
+	pub fn encode(&self) -> Vec<u8> {
+		let mut data = Vec::with_capacity(HEADER_SIZE);
+		data.extend_from_slice(&PROTOCOL_MAGIC);
+		data.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+		bincode::serialize_into(&mut data, self).expect("Failed to serialize packet");
+		data
+	}
+
+	// Check the header before attempting to deserialize the packet
+	pub fn decode(data: &[u8]) -> Result<ReditPacket, PacketError> {
+		if data.len() < HEADER_SIZE || data[..PROTOCOL_MAGIC.len()] != PROTOCOL_MAGIC {
+			return Err(PacketError::BadMagic);
+		}
+
+		let version = u16::from_le_bytes([data[4], data[5]]);
+		if version != PROTOCOL_VERSION {
+			return Err(PacketError::VersionMismatch {
+				expected: PROTOCOL_VERSION,
+				received: version,
+			});
+		}
+
+		bincode::deserialize(&data[HEADER_SIZE..]).map_err(PacketError::Malformed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_packet_round_trip() {
+		let packet = ReditPacket::Hello(Hello {
+			capabilities: Capabilities::local(),
+		});
+		let decoded = ReditPacket::decode(&packet.encode()).unwrap();
+		assert_eq!(packet, decoded);
+	}
+
+	#[test]
+	fn test_version_mismatch() {
+		let mut data = ReditPacket::RequestScanStore(RequestScanStore {}).encode();
+		data[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+
+		match ReditPacket::decode(&data) {
+			Err(PacketError::VersionMismatch { expected, received }) => {
+				assert_eq!(expected, PROTOCOL_VERSION);
+				assert_eq!(received, PROTOCOL_VERSION + 1);
+			}
+			other => panic!("Expected a version mismatch, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_bad_magic() {
+		let data = bincode::serialize(&ReditPacket::RequestScanStore(RequestScanStore {})).unwrap();
+		assert!(matches!(ReditPacket::decode(&data), Err(PacketError::BadMagic)));
+
+		// Builds from before the header sent the bare bincode of their packets, whose variants
+		// went up to ScanStore
+		for tag in 0u32..=7 {
+			let mut data = tag.to_le_bytes().to_vec();
+			data.extend_from_slice(&[0u8; 32]);
+			assert!(matches!(ReditPacket::decode(&data), Err(PacketError::BadMagic)));
+		}
+	}
+
+	#[test]
+	fn test_scan_store_signed_bytes_are_canonical() {
+		let addresses: Vec<IpAddr> = (1..64).map(|i| IpAddr::from([10, 0, 0, i])).collect();
+		let forward = ScanStore {
+			store: addresses.iter().copied().collect(),
+			public_key: None,
+			signature: Vec::new(),
+		};
+		let backward = ScanStore {
+			store: addresses.iter().rev().copied().collect(),
+			public_key: None,
+			signature: Vec::new(),
+		};
+
+		assert_eq!(forward.signed_bytes(), backward.signed_bytes());
+	}
+
+	#[test]
+	fn test_catalog_pages() {
+		let entries: Vec<CatalogEntry> = (0..100)
+			.map(|share_id| CatalogEntry {
+				share_id,
+				name: format!("dataset-{}.tar", share_id),
+				files_size: 1 << 30,
+				packaging: PackagingType::None,
+				availability: Availability {
+					expires_at: Some(1_735_689_600),
+					downloads_left: Some(3),
+					window: None,
+				},
+			})
+			.collect();
+
+		let mut listed = Vec::new();
+		let mut start = 0;
+		loop {
+			let page = Catalog::page(entries.iter().skip(start).cloned(), 1200);
+			let next = page.next;
+			assert!(ReditPacket::Catalog(page.clone()).encode().len() <= 1200);
+			listed.extend(page.shares);
+			match next {
+				Some(next) => start = next as usize,
+				None => break,
+			}
+		}
+		assert_eq!(listed, entries);
+	}
+
+	#[test]
+	fn test_negotiate() {
+		let local = Capabilities::local();
+		let mut remote = Capabilities::local();
+		remote.packaging = vec![PackagingType::None];
+		remote.max_payload_size = 1024;
+
+		let negotiated = local.negotiate(&remote).unwrap();
+		assert_eq!(negotiated.packaging, vec![PackagingType::None]);
+		assert_eq!(negotiated.max_payload_size, 1024);
+
+		remote.ciphers.clear();
+		assert_eq!(local.negotiate(&remote), Err(RejectReason::NoCommonCipher));
+
+		remote.protocol_version = 0;
+		assert_eq!(
+			local.negotiate(&remote),
+			Err(RejectReason::VersionMismatch {
+				expected: PROTOCOL_VERSION,
+				received: 0
+			})
+		);
+	}
 }
 
@@ -1,4 +1,6 @@
+use std::fs;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -8,18 +10,32 @@ use local_ip_address::local_ip;
 
 pub fn get_local_ip() -> IpAddr {
 	match local_ip() {
-		Ok(ip) => {
-			return ip;
-		}
+		Ok(ip) => ip,
 		Err(e) => {
 			log_error(&format!("Failed to get local IP address: {}", e));
 
 			// Return a default IP address in case of error
-			return IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+			IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))
 		}
 	}
 }
 
+// Directory Redit keeps its persistent state in, `$REDIT_HOME` or `~/.redit`
+pub fn redit_dir() -> PathBuf {
+	let dir = match std::env::var_os("REDIT_HOME") {
+		Some(dir) => PathBuf::from(dir),
+		None => match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+			Some(home) => PathBuf::from(home).join(".redit"),
+			None => PathBuf::from(".redit"),
+		},
+	};
+
+	if let Err(e) = fs::create_dir_all(&dir) {
+		log_error(&format!("Failed to create {}: {}", dir.display(), e));
+	}
+	dir
+}
+
 #[derive(Clone)]
 pub struct CancellationToken {
 	cancelled: Arc<AtomicBool>,
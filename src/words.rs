@@ -1 +1,2 @@
+// 1024 common English words, every word of a generated passphrase adds 10 bits of entropy
 pub const WORDS: [&str; 1024] = ["time","people","way","life","years","world","man","state","work","system","new","part","number","case","government","day","states","men","children","women","example","power","information","development","order","group","place","use","data","law","god","water","university","war","point","year","figure","process","fact","york","family","school","form","house","others","end","chapter","research","history","home","table","control","hand","value","course","study","area","united","book","things","period","press","times","society","country","problem","something","nature","level","person","body","business","education","company","city","john","court","problems","effect","words","policy","section","days","name","child","interest","service","side","action","analysis","line","rate","head","terms","question","members","land","age","party","view","position","sense","type","london","nothing","cases","studies","father","death","community","health","groups","results","language","conditions","act","production","model","change","students","mother","knowledge","century","theory","church","field","program","role","class","systems","room","changes","experience","woman","mind","structure","money","result","kind","matter","management","air","eyes","areas","evidence","function","word","growth","office","market","services","method","energy","percent","art","values","countries","night","reason","population","treatment","effects","thing","force","rights","science","trade","food","situation","king","care","support","activity","face","relationship","material","idea","movement","means","president","patients","love","source","america","basis","surface","paper","heart","culture","space","light","activities","story","practice","amount","property","approach","national","size","organization","questions","report","union","department","income","books","behavior","plan","ways","capital","addition","price","series","anything","response","workers","blood","cells","today","journal","factors","design","account","attention","methods","hands","industry","moment","cost","need","performance","committee","relations","months","fig","types","decision","son","test","forms","pressure","levels","job","purpose","term","center","range","issues","quality","letter","hours","parts","lines","south","association","difference","project","distribution","lord","character","college","disease","resources","parents","river","wife","england","product","labor","text","tax","board","general","region","loss","stage","security","right","authority","ground","music","town","patient","india","council","influence","subject","solution","products","army","door","points","issue","william","feet","review","rule","elements","discussion","bank","forces","training","cell","respect","species","importance","friends","works","rules","everything","application","degree","events","washington","environment","voice","north","street","differences","europe","member","schools","west","march","condition","materials","volume","cent","object","one","ideas","sea","truth","costs","individuals","may","earth","presence","june","status","james","building","persons","programs","road","vol","unit","literature","american","administration","direction","temperature","spirit","list","technology","image","morning","operation","extent","factor","statement","oil","set","economy","concept","reference","ability","george","expression","page","risk","rates","july","student","congress","success","increase","staff","france","numbers","agreement","fire","author","china","friend","rest","peace","laws","commission","length","pattern","sources","someone","april","manner","task","date","interests","site","minutes","computer","functions","flow","freedom","processes","politics","cause","relation","choice","sir","note","principle","context","library","understanding","communication","existence","future","justice","principles","meaning","reasons","construction","phase","article","wall","failure","parties","step","strength","plant","opinion","instance","box","needs","nation","week","companies","weight","code","paul","robert","lot","police","car","distance","religion","exchange","news","reality","meeting","thomas","base","access","teachers","pain","front","effort","middle","goods","memory","units","output","picture","october","january","variety","sample","december","september","families","faith","features","miles","division","speech","characteristics","film","examples","east","minister","procedure","teacher","stock","investment","africa","david","nations","marriage","equipment","germany","efforts","aspects","capacity","return","august","impact","girl","district","letters","thought","charles","institutions","public","leaders","models","demand","protection","network","style","animals","figures","christ","operations","record","reaction","properties","international","husband","boy","circumstances","techniques","arms","jesus","lack","relationships","county","employment","paris","contact","ones","majority","collection","opportunity","none","help","california","requirements","conference","sun","japan","november","description","measures","revolution","objects","notes","formation","planning","standards","iii","conflict","bill","answer","organizations","summer","village","actions","lives","weeks","island","gas","hospital","past","henry","patterns","event","responsibility","introduction","philosophy","progress","scale","names","eye","color","everyone","sales","plants","frequency","decisions","purposes","policies","places","argument","supply","mass","title","brother","soil","english","definition","powers","hall","resistance","contrast","heat","media","subjects","sort","structures","prices","smith","tradition","floor","institute","charge","britain","balance","self","papers","february","hair","skills","turn","element","contract","mary","affairs","identity","game","labour","attempt","connection","equation","command","reports","team","anyone","san","concentration","race","beginning","benefits","index","secretary","classes","credit","master","steps","tree","hour","window","motion","possibility","survey","social","bed","play","will","interpretation","fear","strategy","concern","records","chicago","items","message","employees","director","skin","americans","van","officers","drug","tests","content","ratio","evaluation","doubt","chance","fields","file","half","plans","components","desire","month","richard","procedures","facts","hill","daughter","canada","corporation","soul","variables","top","measure","stone","trees","spring","individual","stories","brain","details","advantage","machine","cambridge","officer","constitution","girls","agency","cities","comparison","error","location","path","selection","peter","piece","views","birth","search","matters","leadership","evening","feeling","glass","background","insurance","scene","boys","assessment","practices","post","attitude","technique","mouth","professor","trial","aid","deal","fish","feelings","park","appearance","therapy","leader","absence","transfer","sector","iron","lady","generation","duty","oxford","rise","kinds","origin","perspective","battle","defense","goal","back","applications","wood","shape","mark","version","winter","safety","vision","examination","station","conclusion","protein","violence","agent","bodies","regard","significance","doctor","creation","assistance","houses","images","belief","consideration","detail","psychology","funds","pages","attack","reader","hope","speed","user","communities","references","regions","reduction","goals","projects","chief","foundation","career","standard","sites","competition","sequence","lake","bit","judgment","trust","crisis","ship","experiences","address","interaction","cross","acts","concepts","courts","reform","medicine","assembly","firm","passage","input","share","sound","radio","mexico","mode","learning","sections","television","claim","layer","forest","central","statements","combination","orders","difficulty","stress","emphasis","israel","exercise","movements","situations","consciousness","farm","personality","firms","cycle","sign","agents","jews","bar","mission","white","rock","museum","independence","edge","positions","citizens","composition","election","enemy","republic","recognition","accounts","consequences","walls","officials","resolution","arm","edition","participation","baby","sides","campaign","portion","poetry","authors","cash","centre","acid","struggle","indians","governor","opposition","crime","banks","signs","explanation","facilities","authorities","component","articles","manager","valley","von","tissue","writer","representation","horse","intelligence","steel","aspect","audience","client","density","engineering","responses","agriculture","estate","teaching","focus","characters","provisions","software","writers","drugs","experiments","sister","russia","garden","thoughts","markets","opportunities","difficulties","symptoms","damage","publication","youth","kingdom","johnson","feature","coast","transport","housing","fall","trouble","captain","brown","percentage","proportion","arts","michael","medium","joseph","tools","sentence","strategies","look","claims","convention","industries","plane","benefit","documents","troops","attitudes","periods","couple","expansion","observations","categories","document","jobs","empire","mechanism","camp","column","determination","scheme","notice","mountain","confidence","living","block","welfare","evolution","governments","limits","entry","judge","relief","danger","sight","miss","agencies","investigation","ministry","buildings","experiment","pieces","foot","economic","call","personnel","curve","gold","efficiency","bone","soldiers","consumption","sale","wind","notion","increases","black","criticism","findings","institution","democracy","framework","latter","target","total","wave","probability","transition","contribution","permission","reading","distinction","asia","settlement","band","boston"];
\ No newline at end of file
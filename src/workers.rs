@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Jobs of many clients, handed out in turns. A client has at most one job handed out at a
+// time, so a client with many or slow requests ties up one worker and never all of them.
+pub struct FairQueue<K, T> {
+	queues: HashMap<K, VecDeque<T>>,
+	// Clients with queued jobs and none handed out, in the order of their turns
+	turns: VecDeque<K>,
+	busy: HashSet<K>,
+	// Jobs a client may have queued, further ones are dropped
+	limit: usize,
+	// Jobs all clients together may have queued. Clients are told apart by their address,
+	// which is easily spoofed, so the per client limit alone does not bound the queue.
+	total_limit: usize,
+	len: usize,
+}
+
+impl<K: Hash + Eq + Clone, T> FairQueue<K, T> {
+	pub fn new(limit: usize, total_limit: usize) -> Self {
+		FairQueue {
+			queues: HashMap::new(),
+			turns: VecDeque::new(),
+			busy: HashSet::new(),
+			limit,
+			total_limit,
+			len: 0,
+		}
+	}
+
+	// Queue a job of a client, returns false if it was dropped because the client or all of
+	// them have too many
+	pub fn push(&mut self, key: K, job: T) -> bool {
+		if self.len >= self.total_limit {
+			return false;
+		}
+		let queue = self.queues.entry(key.clone()).or_default();
+		if queue.len() >= self.limit {
+			return false;
+		}
+
+		queue.push_back(job);
+		self.len += 1;
+		if queue.len() == 1 && !self.busy.contains(&key) {
+			self.turns.push_back(key);
+		}
+		true
+	}
+
+	// The next job of the client whose turn it is, the client is busy until it is done
+	pub fn pop(&mut self) -> Option<(K, T)> {
+		let key = self.turns.pop_front()?;
+		let queue = self.queues.get_mut(&key)?;
+		let job = queue.pop_front()?;
+		self.len -= 1;
+		if queue.is_empty() {
+			self.queues.remove(&key);
+		}
+
+		self.busy.insert(key.clone());
+		Some((key, job))
+	}
+
+	// A client's job was handled, it queues up for its next turn if it has more
+	pub fn done(&mut self, key: &K) {
+		self.busy.remove(key);
+		if self.queues.contains_key(key) {
+			self.turns.push_back(key.clone());
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.queues.is_empty()
+	}
+}
+
+struct Shared<K, T> {
+	queue: Mutex<(FairQueue<K, T>, bool)>,
+	available: Condvar,
+}
+
+// Threads handling the jobs of a FairQueue. Dropping the pool lets the workers finish
+// the queued jobs and waits for them.
+pub struct WorkerPool<K, T> {
+	shared: Arc<Shared<K, T>>,
+	workers: Vec<JoinHandle<()>>,
+}
+
+impl<K, T> WorkerPool<K, T>
+where
+	K: Hash + Eq + Clone + Send + 'static,
+	T: Send + 'static,
+{
+	pub fn new(workers: usize, limit: usize, total_limit: usize, handler: impl Fn(T) + Send + Sync + 'static) -> Self {
+		let shared = Arc::new(Shared {
+			queue: Mutex::new((FairQueue::new(limit, total_limit), false)),
+			available: Condvar::new(),
+		});
+		let handler = Arc::new(handler);
+
+		let workers = (0..workers.max(1))
+			.map(|_| {
+				let shared = shared.clone();
+				let handler = handler.clone();
+				thread::spawn(move || Self::work(&shared, &*handler))
+			})
+			.collect();
+
+		WorkerPool { shared, workers }
+	}
+
+	fn work(shared: &Shared<K, T>, handler: &(impl Fn(T) + ?Sized)) {
+		loop {
+			let (key, job) = {
+				let mut guard = shared.queue.lock().unwrap();
+				loop {
+					let (queue, closed) = &mut *guard;
+					if let Some(next) = queue.pop() {
+						break next;
+					}
+					if *closed && queue.is_empty() {
+						return;
+					}
+					guard = shared.available.wait(guard).unwrap();
+				}
+			};
+
+			handler(job);
+
+			// The client may have more jobs queued that no worker could take while it was busy
+			shared.queue.lock().unwrap().0.done(&key);
+			shared.available.notify_one();
+		}
+	}
+
+	// Queue a job of a client, returns false if it was dropped
+	pub fn submit(&self, key: K, job: T) -> bool {
+		let queued = self.shared.queue.lock().unwrap().0.push(key, job);
+		if queued {
+			self.shared.available.notify_one();
+		}
+		queued
+	}
+}
+
+impl<K, T> Drop for WorkerPool<K, T> {
+	fn drop(&mut self) {
+		self.shared.queue.lock().unwrap().1 = true;
+		self.shared.available.notify_all();
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::encryption::PayloadCipher;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::mpsc;
+	use std::time::{Duration, Instant};
+
+	#[test]
+	fn test_clients_take_turns() {
+		let mut queue = FairQueue::new(3, 100);
+		for job in 0..3 {
+			assert!(queue.push("flood", job));
+		}
+		assert!(!queue.push("flood", 3), "Jobs beyond the limit are dropped");
+		assert!(queue.push("quiet", 10));
+
+		assert_eq!(queue.pop(), Some(("flood", 0)));
+		assert_eq!(queue.pop(), Some(("quiet", 10)));
+		// The flooding client is busy, nothing else can be handed out
+		assert_eq!(queue.pop(), None);
+
+		queue.done(&"flood");
+		queue.done(&"quiet");
+		assert!(queue.push("quiet", 11));
+		assert_eq!(queue.pop(), Some(("flood", 1)));
+		assert_eq!(queue.pop(), Some(("quiet", 11)));
+	}
+
+	#[test]
+	fn test_many_clients_cannot_grow_the_queue_past_its_limit() {
+		let mut queue = FairQueue::new(3, 100);
+		let mut queued = 0;
+		for client in 0..1000 {
+			for job in 0..3 {
+				if queue.push(client, job) {
+					queued += 1;
+				}
+			}
+		}
+		assert_eq!(queued, 100);
+		assert_eq!(queue.len, 100);
+
+		// Jobs are taken again once others were handed out
+		assert_eq!(queue.pop(), Some((0, 0)));
+		assert!(queue.push(1000, 0));
+		assert!(!queue.push(1001, 0));
+	}
+
+	#[test]
+	fn test_slow_clients_do_not_starve_others() {
+		let completed = Arc::new(Mutex::new(Vec::new()));
+		let recorder = completed.clone();
+		// The slow client's jobs only finish once the test lets them
+		let (release, gate) = mpsc::channel::<()>();
+		let gate = Mutex::new(gate);
+		let pool = WorkerPool::new(2, 64, 1024, move |client: &'static str| {
+			if client == "slow" {
+				let _ = gate.lock().unwrap().recv();
+			}
+			recorder.lock().unwrap().push(client);
+		});
+
+		for _ in 0..10 {
+			pool.submit("slow", "slow");
+		}
+		for _ in 0..20 {
+			pool.submit("fast", "fast");
+		}
+		// Only guards against hanging, the fast jobs finish long before
+		let deadline = Instant::now() + Duration::from_secs(30);
+		while completed.lock().unwrap().len() < 20 {
+			assert!(Instant::now() < deadline, "The fast client waited behind the slow one");
+			thread::sleep(Duration::from_millis(1));
+		}
+		drop(release);
+		drop(pool);
+
+		let completed = completed.lock().unwrap();
+		assert_eq!(completed.len(), 30, "Queued jobs are handled before the pool stops");
+		assert!(
+			completed[..20].iter().all(|client| *client == "fast"),
+			"The fast client's jobs finish while the slow client's are queued"
+		);
+	}
+
+	// Payloads per second served to a growing number of clients, each encrypting 8 KiB payloads.
+	// Run with `cargo test --release bench_client_scaling -- --ignored --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_client_scaling() {
+		const PAYLOADS: usize = 20_000;
+		let workers = thread::available_parallelism().map_or(4, usize::from);
+		println!("{} workers", workers);
+
+		for clients in [1, 2, 4, 8, 16, 32] {
+			// When every client received its last payload, clients that are served in turns finish together
+			let start = Instant::now();
+			let finished = Arc::new(Mutex::new(Vec::new()));
+			let recorder = finished.clone();
+			let served: Vec<AtomicUsize> = (0..clients).map(|_| AtomicUsize::new(0)).collect();
+			let cipher = PayloadCipher::new(&[7u8; 32], [0u8; 8]);
+			let data = vec![0u8; 8192];
+			let pool = WorkerPool::new(workers, PAYLOADS, clients * PAYLOADS, move |(client, index): (usize, u32)| {
+				std::hint::black_box(cipher.encrypt(&data, index, PAYLOADS as u32));
+				if served[client].fetch_add(1, Ordering::Relaxed) + 1 == PAYLOADS / clients {
+					recorder.lock().unwrap().push(start.elapsed());
+				}
+			});
+
+			for index in 0..PAYLOADS / clients * clients {
+				let client = index % clients;
+				pool.submit(client, (client, index as u32));
+			}
+			drop(pool);
+			let elapsed = start.elapsed();
+
+			let finished = finished.lock().unwrap();
+			println!(
+				"{:>2} clients: {:>8.0} payloads/s, first client done after {:?}, last after {:?}",
+				clients,
+				(PAYLOADS / clients * clients) as f64 / elapsed.as_secs_f64(),
+				finished.iter().min().unwrap(),
+				finished.iter().max().unwrap()
+			);
+		}
+	}
+}